@@ -0,0 +1,339 @@
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Spanned, Value};
+
+type Validator = fn(String) -> Result<(), String>;
+
+enum Kind {
+    Dir,
+    Dirs,
+    Flag,
+    Single(Validator),
+    List(Validator),
+}
+
+// Keys allowed in a profile, with the names of the command line arguments they stand for
+const KEYS: [(&str, &str, Kind); 23] = [
+    ("dirs", "dirs", Kind::Dirs),
+    ("dir-a", "dir_a", Kind::Dir),
+    ("dir-b", "dir_b", Kind::Dir),
+    ("watch", "interval", Kind::Single(crate::is_valid_uint)),
+    ("exclude", "exclude", Kind::List(crate::is_valid_pattern)),
+    ("include", "include", Kind::List(crate::is_valid_pattern)),
+    ("filter", "filter", Kind::List(crate::is_valid_filter_rule)),
+    ("conflict", "conflict", Kind::Single(crate::is_valid_policy)),
+    (
+        "conflict-rule",
+        "conflict_rule",
+        Kind::List(crate::is_valid_rule),
+    ),
+    (
+        "max-delete",
+        "max_delete",
+        Kind::Single(crate::is_valid_count),
+    ),
+    (
+        "max-delete-percent",
+        "max_delete_percent",
+        Kind::Single(crate::is_valid_percent),
+    ),
+    ("allow-mass-delete", "allow_mass_delete", Kind::Flag),
+    ("trash", "trash", Kind::Flag),
+    (
+        "trash-days",
+        "trash_days",
+        Kind::Single(crate::is_valid_uint),
+    ),
+    (
+        "trash-size",
+        "trash_size",
+        Kind::Single(crate::is_valid_uint),
+    ),
+    ("hash", "hash", Kind::Flag),
+    (
+        "mtime-tolerance",
+        "tolerance",
+        Kind::Single(crate::is_valid_seconds),
+    ),
+    ("settle", "settle", Kind::Single(crate::is_valid_count)),
+    ("threads", "threads", Kind::Single(crate::is_valid_uint)),
+    ("full-scan", "full_scan", Kind::Single(crate::is_valid_uint)),
+    ("trust-dir-mtime", "trust_dir_mtime", Kind::Flag),
+    ("no-inotify", "no_inotify", Kind::Flag),
+    ("index-in-tree", "index_in_tree", Kind::Flag),
+];
+
+// Keys that only have an effect together with a flag, like on the command line
+const REQUIRES: [(&str, &str); 2] = [("trash-days", "trash"), ("trash-size", "trash")];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    profile: HashMap<String, HashMap<String, Spanned<Value>>>,
+}
+
+/// Option values of a profile, by argument name.
+#[derive(Default)]
+pub struct Profile {
+    values: HashMap<&'static str, Vec<String>>,
+}
+
+/// The config file is looked for in $XDG_CONFIG_HOME, or in ~/.config if that isn't set.
+pub fn default_config_path() -> PathBuf {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").unwrap_or_default()).join(".config"),
+    };
+    config_dir.join("twowaysync").join("config.toml")
+}
+
+fn line_of(content: &str, offset: usize) -> usize {
+    content[..offset].matches('\n').count() + 1
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.to_string()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        _ => None,
+    }
+}
+
+// Directories may start with ~, and relative ones are relative to the config file
+fn expand_dir(dir: &str, base: &Path) -> PathBuf {
+    match dir.strip_prefix("~/") {
+        Some(rest) => PathBuf::from(std::env::var_os("HOME").unwrap_or_default()).join(rest),
+        None => base.join(dir),
+    }
+}
+
+fn parse_value(kind: &Kind, value: &Value, base: &Path) -> Result<Option<Vec<String>>, String> {
+    let values = match (kind, value) {
+        (Kind::Flag, Value::Boolean(true)) => Vec::new(),
+        (Kind::Flag, Value::Boolean(false)) => return Ok(None),
+        (Kind::Flag, _) => return Err(String::from("Expected true or false")),
+        (Kind::List(_) | Kind::Dirs, Value::Array(items)) => items
+            .iter()
+            .map(scalar)
+            .collect::<Option<Vec<String>>>()
+            .ok_or_else(|| String::from("Expected a list of strings"))?,
+        (Kind::Dirs, _) => return Err(String::from("Expected a list of directories")),
+        (_, value) => {
+            vec![scalar(value).ok_or_else(|| String::from("Expected a string or a number"))?]
+        }
+    };
+    match kind {
+        Kind::Dir | Kind::Dirs => {
+            let mut dirs = Vec::new();
+            for value in values.iter() {
+                let dir = expand_dir(value, base).to_string_lossy().to_string();
+                crate::is_valid_path(dir.clone())?;
+                dirs.push(dir);
+            }
+            Ok(Some(dirs))
+        }
+        Kind::Single(validator) | Kind::List(validator) => {
+            for value in values.iter() {
+                validator(value.to_string())?;
+            }
+            Ok(Some(values))
+        }
+        Kind::Flag => Ok(Some(values)),
+    }
+}
+
+fn parse_profile(content: &str, name: &str, base: &Path) -> Result<Profile, String> {
+    let config: ConfigFile = toml::from_str(content).map_err(|e| e.to_string())?;
+    let entries = match config.profile.get(name) {
+        Some(entries) => entries,
+        None => {
+            let mut names = config.profile.keys().cloned().collect::<Vec<String>>();
+            names.sort();
+            return Err(format!(
+                "No profile named '{}', available profiles: {}",
+                name,
+                names.join(", ")
+            ));
+        }
+    };
+    let mut entries = entries.iter().collect::<Vec<(&String, &Spanned<Value>)>>();
+    entries.sort_by_key(|(_, value)| value.span().start);
+    let mut profile = Profile::default();
+    let mut lines = Vec::new();
+    for (key, value) in entries {
+        let line = line_of(content, value.span().start);
+        lines.push((key, line));
+        let (arg, kind) = match KEYS.iter().find(|(k, _, _)| k == key) {
+            Some((_, arg, kind)) => (arg, kind),
+            None => return Err(format!("line {}: unknown option '{}'", line, key)),
+        };
+        match parse_value(kind, value.get_ref(), base) {
+            Ok(Some(values)) => {
+                profile.values.insert(arg, values);
+            }
+            Ok(None) => {}
+            Err(e) => return Err(format!("line {}: {}: {}", line, key, e)),
+        }
+    }
+    if profile.values.contains_key("dirs")
+        && (profile.values.contains_key("dir_a") || profile.values.contains_key("dir_b"))
+    {
+        return Err(String::from("Use either dirs, or dir-a and dir-b"));
+    }
+    for (key, line) in lines {
+        let flag = match REQUIRES.iter().find(|(k, _)| k == key) {
+            Some((_, flag)) => flag,
+            None => continue,
+        };
+        let enabled = KEYS
+            .iter()
+            .find(|(k, _, _)| k == flag)
+            .is_some_and(|(_, arg, _)| profile.values.contains_key(arg));
+        if !enabled {
+            return Err(format!("line {}: {} requires {} = true", line, key, flag));
+        }
+    }
+    Ok(profile)
+}
+
+/// Read a profile from a config file, and check its values like the command line arguments.
+pub fn load_profile(path: &Path, name: &str) -> Result<Profile, Box<dyn Error>> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let base = path.parent().unwrap_or_else(|| Path::new("/"));
+    let profile =
+        parse_profile(&content, name, base).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(profile)
+}
+
+/// Option values from the command line, falling back to the values of a profile.
+pub struct Settings<'a> {
+    matches: &'a ArgMatches<'a>,
+    profile: Profile,
+}
+
+impl<'a> Settings<'a> {
+    pub fn new(matches: &'a ArgMatches<'a>, profile: Option<Profile>) -> Settings<'a> {
+        Settings {
+            matches,
+            profile: profile.unwrap_or_default(),
+        }
+    }
+
+    pub fn is_present(&self, name: &str) -> bool {
+        self.matches.is_present(name) || self.profile.values.contains_key(name)
+    }
+
+    pub fn value_of(&self, name: &str) -> Option<&str> {
+        self.matches.value_of(name).or_else(|| {
+            self.profile
+                .values
+                .get(name)
+                .and_then(|values| values.first())
+                .map(|value| value.as_str())
+        })
+    }
+
+    pub fn values_of(&self, name: &str) -> Option<Vec<&str>> {
+        match self.matches.values_of(name) {
+            Some(values) => Some(values.collect()),
+            None => self
+                .profile
+                .values
+                .get(name)
+                .map(|values| values.iter().map(|value| value.as_str()).collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[profile.work]
+dir-a = "/tmp"
+dir-b = "."
+watch = 10
+exclude = ["*.tmp", "build"]
+hash = true
+trash = false
+
+[profile.broken]
+watch = 10
+max-delete-percent = 150
+"#;
+
+    #[test]
+    fn profile_is_parsed() {
+        let profile = parse_profile(CONFIG, "work", Path::new("/")).unwrap();
+        assert_eq!(profile.values["dir_a"], vec!["/tmp"]);
+        assert_eq!(profile.values["dir_b"], vec!["/."]);
+        assert_eq!(profile.values["interval"], vec!["10"]);
+        assert_eq!(profile.values["exclude"], vec!["*.tmp", "build"]);
+        assert!(profile.values.contains_key("hash"));
+        assert!(!profile.values.contains_key("trash"));
+    }
+
+    #[test]
+    fn invalid_value_reports_line() {
+        let err = parse_profile(CONFIG, "broken", Path::new("/"))
+            .err()
+            .unwrap();
+        assert!(err.starts_with("line 12: max-delete-percent:"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_key_reports_line() {
+        let config = "[profile.x]\nwatch = 1\nwach = 1\n";
+        let err = parse_profile(config, "x", Path::new("/")).err().unwrap();
+        assert_eq!(err, "line 3: unknown option 'wach'");
+    }
+
+    #[test]
+    fn profile_with_dirs() {
+        let config = "[profile.x]\ndirs = [\"/tmp\", \".\", \"/\"]\n";
+        let profile = parse_profile(config, "x", Path::new("/")).unwrap();
+        assert_eq!(profile.values["dirs"], vec!["/tmp", "/.", "/"]);
+        let config = "[profile.x]\ndirs = [\"/tmp\", \".\"]\ndir-a = \"/\"\n";
+        let err = parse_profile(config, "x", Path::new("/")).err().unwrap();
+        assert_eq!(err, "Use either dirs, or dir-a and dir-b");
+        let config = "[profile.x]\ndirs = \"/tmp\"\n";
+        let err = parse_profile(config, "x", Path::new("/")).err().unwrap();
+        assert_eq!(err, "line 2: dirs: Expected a list of directories");
+    }
+
+    #[test]
+    fn trash_options_require_trash() {
+        let config = "[profile.x]\nwatch = 1\ntrash-days = 7\n";
+        let err = parse_profile(config, "x", Path::new("/")).err().unwrap();
+        assert_eq!(err, "line 3: trash-days requires trash = true");
+        let config = "[profile.x]\ntrash = false\ntrash-size = 100\n";
+        let err = parse_profile(config, "x", Path::new("/")).err().unwrap();
+        assert_eq!(err, "line 3: trash-size requires trash = true");
+        let config = "[profile.x]\ntrash-days = 7\ntrash = true\n";
+        let profile = parse_profile(config, "x", Path::new("/")).unwrap();
+        assert_eq!(profile.values["trash_days"], vec!["7"]);
+    }
+
+    #[test]
+    fn missing_profile() {
+        let err = parse_profile(CONFIG, "home", Path::new("/")).err().unwrap();
+        assert_eq!(
+            err,
+            "No profile named 'home', available profiles: broken, work"
+        );
+    }
+
+    #[test]
+    fn syntax_error_reports_line() {
+        let config = "[profile.x]\nwatch = 1\nexclude = [\"a\"\n";
+        let err = parse_profile(config, "x", Path::new("/")).err().unwrap();
+        assert!(err.contains("line 3"), "{}", err);
+    }
+}
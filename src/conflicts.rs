@@ -0,0 +1,1330 @@
+use self::Resolution::{BothChanged, DropBoth, KeepCopy, KeepMaster};
+use crate::datatypes::{compare_mtime, ChangeType, DiffItem, FileType, SyncAction};
+use chrono::Local;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const CONFLICTLOGNAME: &str = ".twoway-conflicts.log";
+
+const POLICYNAMES: [&str; 7] = [
+    "newest",
+    "prefer-a",
+    "prefer-b",
+    "larger",
+    "keep-both",
+    "skip",
+    "interactive",
+];
+
+/// How to handle a file that was changed in both directories.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    Newest,
+    PreferA,
+    PreferB,
+    Larger,
+    KeepBoth,
+    Skip,
+    Interactive,
+}
+
+impl ConflictPolicy {
+    pub fn parse(name: &str) -> Result<ConflictPolicy, String> {
+        match name.to_lowercase().as_str() {
+            "newest" => Ok(ConflictPolicy::Newest),
+            "prefer-a" => Ok(ConflictPolicy::PreferA),
+            "prefer-b" => Ok(ConflictPolicy::PreferB),
+            "larger" => Ok(ConflictPolicy::Larger),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            "skip" | "skip-and-report" => Ok(ConflictPolicy::Skip),
+            "interactive" => Ok(ConflictPolicy::Interactive),
+            _ => Err(format!(
+                "Unknown conflict policy {}, use one of {}",
+                name,
+                POLICYNAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConflictPolicy::Newest => write!(f, "newest"),
+            ConflictPolicy::PreferA => write!(f, "prefer-a"),
+            ConflictPolicy::PreferB => write!(f, "prefer-b"),
+            ConflictPolicy::Larger => write!(f, "larger"),
+            ConflictPolicy::KeepBoth => write!(f, "keep-both"),
+            ConflictPolicy::Skip => write!(f, "skip"),
+            ConflictPolicy::Interactive => write!(f, "interactive"),
+        }
+    }
+}
+
+/// How the user decided to resolve a conflict.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    /// Keep the version from this directory
+    Keep(usize),
+    KeepBoth,
+    Skip,
+}
+
+/// Split a rule given as "pattern=policy".
+pub fn parse_rule(rule: &str) -> Result<(String, ConflictPolicy), String> {
+    match rule.rfind('=') {
+        Some(idx) => {
+            let pattern = &rule[..idx];
+            Glob::new(pattern).map_err(|_| String::from("Invalid pattern"))?;
+            Ok((
+                pattern.to_string(),
+                ConflictPolicy::parse(&rule[idx + 1..])?,
+            ))
+        }
+        None => Err(String::from("Expected a rule like pattern=policy")),
+    }
+}
+
+/// A default policy, and policies for paths matching a pattern.
+/// The first matching pattern decides.
+#[derive(Clone)]
+pub struct ConflictPolicies {
+    default: ConflictPolicy,
+    globs: GlobSet,
+    policies: Vec<ConflictPolicy>,
+}
+
+impl ConflictPolicies {
+    pub fn new(
+        default: ConflictPolicy,
+        rules: &[(String, ConflictPolicy)],
+    ) -> Result<ConflictPolicies, Box<dyn Error>> {
+        let mut builder = GlobSetBuilder::new();
+        for (pattern, _) in rules.iter() {
+            builder.add(Glob::new(pattern)?);
+        }
+        Ok(ConflictPolicies {
+            default,
+            globs: builder.build()?,
+            policies: rules.iter().map(|(_, policy)| *policy).collect(),
+        })
+    }
+
+    pub fn for_path(&self, path: &Path) -> ConflictPolicy {
+        match self.globs.matches(path).into_iter().min() {
+            Some(idx) => self.policies[idx],
+            None => self.default,
+        }
+    }
+}
+
+/// A file that was changed in two directories since the last sync. The directories are
+/// given by their position in the group, A being the first one.
+/// With the keep-both policy the losing version is kept in all directories
+/// under a new name. Skipped conflicts are left unchanged everywhere.
+#[derive(Clone)]
+pub struct Conflict {
+    pub path: PathBuf,
+    pub kept: usize,
+    pub lost: usize,
+    pub copy: Option<PathBuf>,
+    pub policy: ConflictPolicy,
+}
+
+/// The letter a directory is shown with, A for the first one.
+pub fn root_name(idx: usize) -> char {
+    (b'A' + idx as u8) as char
+}
+
+// Insert the conflict marker before the extension, "report.txt" becomes
+// "report.conflict-B-20191024-153012.txt"
+fn conflict_name(path: &Path, side: &str, stamp: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!(
+            "{}.conflict-{}-{}.{}",
+            stem,
+            side,
+            stamp,
+            ext.to_string_lossy()
+        ),
+        None => format!("{}.conflict-{}-{}", stem, side, stamp),
+    };
+    path.with_file_name(name)
+}
+
+impl Conflict {
+    pub fn new(path: &Path, kept: usize, lost: usize, policy: ConflictPolicy) -> Conflict {
+        let copy = if policy == ConflictPolicy::KeepBoth {
+            let stamp = Local::now().format("%Y%m%d-%H%M%S").to_string();
+            let side = root_name(lost).to_string();
+            Some(conflict_name(path, &side, &stamp))
+        } else {
+            None
+        };
+        Conflict {
+            path: path.to_path_buf(),
+            kept,
+            lost,
+            copy,
+            policy,
+        }
+    }
+
+    /// The two directories with a version of the path, the first one first.
+    pub fn sides(&self) -> (usize, usize) {
+        (self.kept.min(self.lost), self.kept.max(self.lost))
+    }
+
+    /// True if the conflict is left for the user to resolve.
+    pub fn is_held(&self) -> bool {
+        self.policy == ConflictPolicy::Skip || self.policy == ConflictPolicy::Interactive
+    }
+
+    /// Actions for copying the losing version to the conflict copy in all directories.
+    pub fn actions(&self, roots: &[PathBuf]) -> Vec<SyncAction> {
+        let copy = match &self.copy {
+            Some(copy) => copy,
+            None => return Vec::new(),
+        };
+        roots
+            .iter()
+            .map(|root| SyncAction::CopyFile {
+                src: roots[self.lost].join(&self.path),
+                dest: root.join(copy),
+            })
+            .collect()
+    }
+
+    /// The action that replaces the losing version with the kept one.
+    pub fn overwrite(&self, roots: &[PathBuf]) -> SyncAction {
+        SyncAction::CopyFile {
+            src: roots[self.kept].join(&self.path),
+            dest: roots[self.lost].join(&self.path),
+        }
+    }
+
+    /// A conflict copy is resolved once the user has removed it.
+    /// Held conflicts are detected again at every sync until they are resolved.
+    pub fn is_resolved(&self, roots: &[PathBuf]) -> bool {
+        match &self.copy {
+            Some(copy) => roots
+                .iter()
+                .all(|root| fs::symlink_metadata(root.join(copy)).is_err()),
+            None => !self.is_held(),
+        }
+    }
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (kept, lost) = (root_name(self.kept), root_name(self.lost));
+        match (&self.copy, self.policy) {
+            (Some(copy), _) => write!(
+                f,
+                "{}: kept the version from {}, the version from {} was saved as {}",
+                self.path.display(),
+                kept,
+                lost,
+                copy.display()
+            ),
+            (None, ConflictPolicy::Skip) => write!(
+                f,
+                "{}: skipped, both versions were left unchanged",
+                self.path.display()
+            ),
+            (None, ConflictPolicy::Interactive) => write!(
+                f,
+                "{}: waiting for a decision, both versions were left unchanged",
+                self.path.display()
+            ),
+            (None, policy) => write!(
+                f,
+                "{}: kept the version from {} ({}), the version from {} was overwritten",
+                self.path.display(),
+                kept,
+                policy,
+                lost
+            ),
+        }
+    }
+}
+
+/// What to do with a path that has changes on both sides.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Resolution {
+    /// Both sides removed it, nothing left to sync
+    DropBoth,
+    /// Apply the change from master and drop the one from copy
+    KeepMaster,
+    /// Apply the change from copy and drop the one from master
+    KeepCopy,
+    /// The newest version wins, unless the item is a file with different
+    /// contents on the two sides. Then the conflict policy decides.
+    BothChanged,
+}
+
+// Position of a change in the resolution table. A renamed item is new at its path.
+fn change_index(change: &ChangeType) -> usize {
+    match change {
+        ChangeType::Newer => 0,
+        ChangeType::Older => 1,
+        ChangeType::Modified => 2,
+        ChangeType::NewOnly | ChangeType::Renamed(_) => 3,
+        ChangeType::RefOnly => 4,
+        ChangeType::TypeChanged => 5,
+    }
+}
+
+// Rows are the change in master, columns the change in copy. A change always wins over a removal,
+// so an item that was changed on one side and deleted on the other is resurrected.
+#[rustfmt::skip]
+const RESOLUTIONS: [[Resolution; 6]; 6] = [
+    //   Newer        Older        Modified     NewOnly      RefOnly     TypeChanged
+    [BothChanged, BothChanged, BothChanged, BothChanged, KeepMaster, BothChanged], // Newer
+    [BothChanged, BothChanged, BothChanged, BothChanged, KeepMaster, BothChanged], // Older
+    [BothChanged, BothChanged, BothChanged, BothChanged, KeepMaster, BothChanged], // Modified
+    [BothChanged, BothChanged, BothChanged, BothChanged, KeepMaster, BothChanged], // NewOnly
+    [KeepCopy,    KeepCopy,    KeepCopy,    KeepCopy,    DropBoth,   KeepCopy   ], // RefOnly
+    [BothChanged, BothChanged, BothChanged, BothChanged, KeepMaster, BothChanged], // TypeChanged
+];
+
+// The policy for a conflict between master and copy. A decision to keep the version of one
+// of them is prefer-a for master and prefer-b for copy. A decision for another directory
+// doesn't tell which of these two to keep, then the configured policy decides.
+fn policy_for(
+    path: &PathBuf,
+    root_master: usize,
+    root_copy: usize,
+    policies: &ConflictPolicies,
+    decisions: &HashMap<PathBuf, Decision>,
+) -> ConflictPolicy {
+    match decisions.get(path) {
+        Some(Decision::Keep(root)) if *root == root_master => ConflictPolicy::PreferA,
+        Some(Decision::Keep(root)) if *root == root_copy => ConflictPolicy::PreferB,
+        Some(Decision::KeepBoth) => ConflictPolicy::KeepBoth,
+        Some(Decision::Skip) => ConflictPolicy::Skip,
+        _ => policies.for_path(path),
+    }
+}
+
+// Decide which side to keep for a file with different contents on both sides
+fn keep_master_by_policy(
+    policy: ConflictPolicy,
+    diffitem_master: &DiffItem,
+    diffitem_copy: &DiffItem,
+    master_is_newest: bool,
+) -> bool {
+    match policy {
+        ConflictPolicy::PreferA => true,
+        ConflictPolicy::PreferB => false,
+        ConflictPolicy::Larger => match diffitem_master.size.cmp(&diffitem_copy.size) {
+            Ordering::Greater => true,
+            Ordering::Less => false,
+            Ordering::Equal => master_is_newest,
+        },
+        _ => master_is_newest,
+    }
+}
+
+// Check if a diff has changes to items inside a directory
+fn has_changes_inside(diff: &HashMap<PathBuf, DiffItem>, path: &PathBuf) -> bool {
+    diff.iter().any(|(other, diffitem)| {
+        other != path && other.starts_with(path) && diffitem.diff != ChangeType::RefOnly
+    })
+}
+
+// The directories the changes of the two diffs were made in. Master may be merged from the
+// diffs of several directories, its changes are from A unless listed.
+struct Sides {
+    master: HashMap<PathBuf, usize>,
+    copy: usize,
+}
+
+impl Sides {
+    fn of_master(&self, path: &PathBuf) -> usize {
+        self.master.get(path).copied().unwrap_or(0)
+    }
+
+    fn of(&self, is_master: bool, path: &PathBuf) -> usize {
+        if is_master {
+            self.of_master(path)
+        } else {
+            self.copy
+        }
+    }
+}
+
+// A removed directory is kept if the other side changed something inside it. It is then
+// recreated on the side where it was removed, also if it was replaced by a file or link.
+// A file that replaced it is saved as a conflict copy if the policy is keep-both.
+fn resurrect_parents(
+    diff_removed: &mut HashMap<PathBuf, DiffItem>,
+    diff_other: &mut HashMap<PathBuf, DiffItem>,
+    removed_is_master: bool,
+    sides: &mut Sides,
+    policies: &ConflictPolicies,
+    decisions: &HashMap<PathBuf, Decision>,
+    conflicts: &mut Vec<Conflict>,
+) {
+    let changed = diff_other
+        .iter()
+        .filter(|(_, diffitem)| diffitem.diff != ChangeType::RefOnly)
+        .map(|(path, _)| path.to_path_buf())
+        .collect::<Vec<PathBuf>>();
+    for path in changed.iter() {
+        // The parent is recreated from the directory that made the change inside it
+        let other_root = sides.of(!removed_is_master, path);
+        for parent in path.ancestors().skip(1) {
+            if diff_other.contains_key(parent) {
+                continue;
+            }
+            let parent = parent.to_path_buf();
+            let removed_root = sides.of(removed_is_master, &parent);
+            match diff_removed.get(&parent) {
+                Some(diffitem) if diffitem.diff == ChangeType::RefOnly => {
+                    let mut diffitem = diff_removed.remove(&parent).unwrap();
+                    diffitem.diff = ChangeType::NewOnly;
+                    diff_other.insert(parent.clone(), diffitem);
+                }
+                Some(diffitem)
+                    if diffitem.diff == ChangeType::TypeChanged
+                        && diffitem.ftype != FileType::Dir =>
+                {
+                    let diffitem = diff_removed.remove(&parent).unwrap();
+                    if diffitem.ftype == FileType::File
+                        && policy_for(&parent, other_root, removed_root, policies, decisions)
+                            == ConflictPolicy::KeepBoth
+                    {
+                        conflicts.push(Conflict::new(
+                            &parent,
+                            other_root,
+                            removed_root,
+                            ConflictPolicy::KeepBoth,
+                        ));
+                    }
+                    diff_other.insert(
+                        parent.clone(),
+                        DiffItem {
+                            diff: ChangeType::TypeChanged,
+                            ftype: FileType::Dir,
+                            mtime: diffitem.mtime,
+                            size: 0,
+                            hash: None,
+                        },
+                    );
+                }
+                _ => continue,
+            }
+            if !removed_is_master {
+                sides.master.insert(parent, other_root);
+            }
+        }
+    }
+}
+
+// Remove contradicting changes from two diffs, so that each path is only changed in one direction.
+// Returns the files that were changed on both sides.
+fn solve_conflicts(
+    diff_master: &mut HashMap<PathBuf, DiffItem>,
+    diff_copy: &mut HashMap<PathBuf, DiffItem>,
+    sides: &mut Sides,
+    mtime_tolerance: i128,
+    policies: &ConflictPolicies,
+    decisions: &HashMap<PathBuf, Decision>,
+) -> Result<Vec<Conflict>, Box<dyn Error>> {
+    let mut conflicts = Vec::new();
+    let paths = diff_master
+        .keys()
+        .filter(|path| diff_copy.contains_key(*path))
+        .cloned()
+        .collect::<Vec<PathBuf>>();
+    for path in paths.iter() {
+        // Already dropped along with a parent that is no longer a directory
+        let (diffitem_master, diffitem_copy) = match (diff_master.get(path), diff_copy.get(path)) {
+            (Some(master), Some(copy)) => (master, copy),
+            _ => continue,
+        };
+        let (root_master, root_copy) = (sides.of_master(path), sides.copy);
+        let master_is_newest =
+            compare_mtime(diffitem_master.mtime, diffitem_copy.mtime, mtime_tolerance)
+                != Ordering::Less;
+        // A removed item doesn't exist anymore, whatever type it had
+        let type_differs = diffitem_master.ftype != diffitem_copy.ftype
+            && diffitem_master.diff != ChangeType::RefOnly
+            && diffitem_copy.diff != ChangeType::RefOnly;
+        let keep_master = match RESOLUTIONS[change_index(&diffitem_master.diff)]
+            [change_index(&diffitem_copy.diff)]
+        {
+            DropBoth => None,
+            KeepMaster => Some(true),
+            KeepCopy => Some(false),
+            // Never synced, for example left over from the first merge. Nothing tells which
+            // one to keep, so both are left alone until the user decides or removes one
+            BothChanged
+                if type_differs
+                    && diffitem_master.diff == ChangeType::NewOnly
+                    && diffitem_copy.diff == ChangeType::NewOnly =>
+            {
+                match decisions.get(path) {
+                    Some(Decision::Keep(root)) if *root == root_master => Some(true),
+                    Some(Decision::Keep(root)) if *root == root_copy => Some(false),
+                    _ => {
+                        conflicts.push(Conflict::new(
+                            path,
+                            root_master,
+                            root_copy,
+                            ConflictPolicy::Skip,
+                        ));
+                        diff_master.retain(|other, _| !other.starts_with(path));
+                        diff_copy.retain(|other, _| !other.starts_with(path));
+                        continue;
+                    }
+                }
+            }
+            BothChanged if type_differs => {
+                // A directory with changes inside wins over an item of another type
+                if diffitem_master.ftype == FileType::Dir && has_changes_inside(diff_master, path) {
+                    Some(true)
+                } else if diffitem_copy.ftype == FileType::Dir
+                    && has_changes_inside(diff_copy, path)
+                {
+                    Some(false)
+                } else {
+                    Some(master_is_newest)
+                }
+            }
+            BothChanged => {
+                if diffitem_master.differs_from(diffitem_copy, mtime_tolerance) {
+                    let policy = policy_for(path, root_master, root_copy, policies, decisions);
+                    let keep_master = keep_master_by_policy(
+                        policy,
+                        diffitem_master,
+                        diffitem_copy,
+                        master_is_newest,
+                    );
+                    let conflict = if keep_master {
+                        Conflict::new(path, root_master, root_copy, policy)
+                    } else {
+                        Conflict::new(path, root_copy, root_master, policy)
+                    };
+                    let held = conflict.is_held();
+                    conflicts.push(conflict);
+                    if held {
+                        None
+                    } else {
+                        Some(keep_master)
+                    }
+                } else {
+                    Some(master_is_newest)
+                }
+            }
+        };
+        let (winner, loser) = match keep_master {
+            Some(true) => (diff_master.get_mut(path).unwrap(), &mut *diff_copy),
+            Some(false) => (diff_copy.get_mut(path).unwrap(), &mut *diff_master),
+            None => {
+                diff_master.remove(path);
+                diff_copy.remove(path);
+                continue;
+            }
+        };
+        let loser_ftype = loser.remove(path).unwrap().ftype;
+        if type_differs {
+            // The winner replaces the entry of the other type
+            winner.diff = ChangeType::TypeChanged;
+            if winner.ftype != FileType::Dir {
+                loser.retain(|other, _| !other.starts_with(path));
+            }
+            if loser_ftype == FileType::File
+                && policy_for(path, root_master, root_copy, policies, decisions)
+                    == ConflictPolicy::KeepBoth
+            {
+                let (kept, lost) = if keep_master == Some(true) {
+                    (root_master, root_copy)
+                } else {
+                    (root_copy, root_master)
+                };
+                conflicts.push(Conflict::new(path, kept, lost, ConflictPolicy::KeepBoth));
+            }
+        }
+    }
+    resurrect_parents(
+        diff_master,
+        diff_copy,
+        true,
+        sides,
+        policies,
+        decisions,
+        &mut conflicts,
+    );
+    resurrect_parents(
+        diff_copy,
+        diff_master,
+        false,
+        sides,
+        policies,
+        decisions,
+        &mut conflicts,
+    );
+    Ok(conflicts)
+}
+
+/// Remove contradicting changes from the diffs of a group of directories, so that each path
+/// is only changed from one of them. The diffs are merged one by one, the first one being
+/// the master of each merge. Items removed in some of the directories stay in the diff of
+/// the first of them, unless they were removed everywhere.
+/// Returns the files that were changed in more than one directory.
+pub fn merge_diffs(
+    diffs: &mut [HashMap<PathBuf, DiffItem>],
+    mtime_tolerance: i128,
+    policies: &ConflictPolicies,
+    decisions: &HashMap<PathBuf, Decision>,
+) -> Result<Vec<Conflict>, Box<dyn Error>> {
+    let mut conflicts = Vec::new();
+    let mut removals: HashMap<PathBuf, usize> = HashMap::new();
+    for diff in diffs.iter() {
+        for (path, _) in diff.iter().filter(|(_, d)| d.diff == ChangeType::RefOnly) {
+            *removals.entry(path.to_path_buf()).or_default() += 1;
+        }
+    }
+    let mut merged = diffs.first_mut().map(std::mem::take).unwrap_or_default();
+    let mut sides = Sides {
+        master: HashMap::new(),
+        copy: 0,
+    };
+    for (idx, diff) in diffs.iter_mut().enumerate().skip(1) {
+        sides.copy = idx;
+        let removed_before = merged
+            .iter()
+            .filter(|(path, d)| {
+                d.diff == ChangeType::RefOnly
+                    && diff.get(*path).map(|d| &d.diff) == Some(&ChangeType::RefOnly)
+            })
+            .map(|(path, d)| (path.to_path_buf(), d.clone()))
+            .collect::<Vec<(PathBuf, DiffItem)>>();
+        let mut diff_copy = std::mem::take(diff);
+        conflicts.append(&mut solve_conflicts(
+            &mut merged,
+            &mut diff_copy,
+            &mut sides,
+            mtime_tolerance,
+            policies,
+            decisions,
+        )?);
+        // Still to be removed from the directories that have it
+        for (path, diffitem) in removed_before.into_iter() {
+            if !merged.contains_key(&path) && !diff_copy.contains_key(&path) {
+                merged.insert(path, diffitem);
+            }
+        }
+        for path in diff_copy.keys() {
+            sides.master.insert(path.to_path_buf(), idx);
+        }
+        merged.extend(diff_copy);
+    }
+    // Held conflicts are left alone everywhere, also if a later directory changed them,
+    // together with everything inside them
+    for conflict in conflicts.iter().filter(|conflict| conflict.is_held()) {
+        merged.retain(|path, _| !path.starts_with(&conflict.path));
+    }
+    for (path, diffitem) in merged.into_iter() {
+        if diffitem.diff == ChangeType::RefOnly && removals.get(&path) == Some(&diffs.len()) {
+            continue;
+        }
+        diffs[sides.of_master(&path)].insert(path, diffitem);
+    }
+    Ok(conflicts)
+}
+
+/// Append the conflicts to the conflict log in all directories.
+pub fn log_conflicts(conflicts: &[Conflict], roots: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    let local_time = Local::now().format("%Y-%m-%d %H:%M:%S");
+    for path in roots.iter() {
+        let mut logfile = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.join(CONFLICTLOGNAME))?;
+        for conflict in conflicts.iter() {
+            writeln!(logfile, "{} {}", local_time, conflict)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn print_conflicts(conflicts: &[Conflict], label: &str) {
+    if conflicts.is_empty() {
+        println!("{}No unresolved conflicts\r", label);
+        return;
+    }
+    println!("{}Unresolved conflicts:\r", label);
+    for conflict in conflicts.iter() {
+        println!("  {}\r", conflict);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEWER: i128 = 2_000_000_000;
+    const OLDER: i128 = 1_000_000_000;
+
+    fn all_changes() -> Vec<ChangeType> {
+        vec![
+            ChangeType::Newer,
+            ChangeType::Older,
+            ChangeType::Modified,
+            ChangeType::NewOnly,
+            ChangeType::RefOnly,
+            ChangeType::TypeChanged,
+        ]
+    }
+
+    fn item(diff: &ChangeType, ftype: FileType, mtime: i128, size: u64) -> DiffItem {
+        DiffItem {
+            diff: diff.clone(),
+            ftype,
+            mtime,
+            size,
+            hash: None,
+        }
+    }
+
+    fn policies(default: ConflictPolicy) -> ConflictPolicies {
+        ConflictPolicies::new(default, &[]).unwrap()
+    }
+
+    fn pair() -> Sides {
+        Sides {
+            master: HashMap::new(),
+            copy: 1,
+        }
+    }
+
+    // Solve a single path, returns if the master and copy changes survived, and the conflicts
+    fn solve_with(
+        master: DiffItem,
+        copy: DiffItem,
+        policies: &ConflictPolicies,
+        decisions: &HashMap<PathBuf, Decision>,
+    ) -> (Option<ChangeType>, Option<ChangeType>, Vec<Conflict>) {
+        let path = PathBuf::from("file.txt");
+        let mut diff_master = HashMap::new();
+        let mut diff_copy = HashMap::new();
+        diff_master.insert(path.clone(), master);
+        diff_copy.insert(path.clone(), copy);
+        let conflicts = solve_conflicts(
+            &mut diff_master,
+            &mut diff_copy,
+            &mut pair(),
+            0,
+            policies,
+            decisions,
+        )
+        .unwrap();
+        (
+            diff_master.remove(&path).map(|d| d.diff),
+            diff_copy.remove(&path).map(|d| d.diff),
+            conflicts,
+        )
+    }
+
+    fn solve(
+        master: DiffItem,
+        copy: DiffItem,
+        policy: ConflictPolicy,
+    ) -> (Option<ChangeType>, Option<ChangeType>, Vec<Conflict>) {
+        solve_with(master, copy, &policies(policy), &HashMap::new())
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Kept {
+        Master,
+        Copy,
+        Neither,
+    }
+
+    fn kept(result: &(Option<ChangeType>, Option<ChangeType>, Vec<Conflict>)) -> Kept {
+        match (result.0.is_some(), result.1.is_some()) {
+            (true, false) => Kept::Master,
+            (false, true) => Kept::Copy,
+            (false, false) => Kept::Neither,
+            (true, true) => panic!("both changes survived"),
+        }
+    }
+
+    // The expected outcome for each pair, when master is the newest and the files differ
+    fn expected(master: &ChangeType, copy: &ChangeType) -> (Kept, bool) {
+        use ChangeType::*;
+        match (master, copy) {
+            (Newer, Newer) => (Kept::Master, true),
+            (Newer, Older) => (Kept::Master, true),
+            (Newer, Modified) => (Kept::Master, true),
+            (Newer, NewOnly) => (Kept::Master, true),
+            (Newer, RefOnly) => (Kept::Master, false),
+            (Older, Newer) => (Kept::Master, true),
+            (Older, Older) => (Kept::Master, true),
+            (Older, Modified) => (Kept::Master, true),
+            (Older, NewOnly) => (Kept::Master, true),
+            (Older, RefOnly) => (Kept::Master, false),
+            (Modified, Newer) => (Kept::Master, true),
+            (Modified, Older) => (Kept::Master, true),
+            (Modified, Modified) => (Kept::Master, true),
+            (Modified, NewOnly) => (Kept::Master, true),
+            (Modified, RefOnly) => (Kept::Master, false),
+            (NewOnly, Newer) => (Kept::Master, true),
+            (NewOnly, Older) => (Kept::Master, true),
+            (NewOnly, Modified) => (Kept::Master, true),
+            (NewOnly, NewOnly) => (Kept::Master, true),
+            (NewOnly, RefOnly) => (Kept::Master, false),
+            (RefOnly, Newer) => (Kept::Copy, false),
+            (RefOnly, Older) => (Kept::Copy, false),
+            (RefOnly, Modified) => (Kept::Copy, false),
+            (RefOnly, NewOnly) => (Kept::Copy, false),
+            (RefOnly, RefOnly) => (Kept::Neither, false),
+            // A type change counts as a change of contents
+            (TypeChanged, copy) => expected(&Newer, copy),
+            (master, TypeChanged) => expected(master, &Newer),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn all_pairs_master_newest() {
+        for master in all_changes().iter() {
+            for copy in all_changes().iter() {
+                let result = solve(
+                    item(master, FileType::File, NEWER, 10),
+                    item(copy, FileType::File, OLDER, 20),
+                    ConflictPolicy::KeepBoth,
+                );
+                let (exp_kept, exp_conflict) = expected(master, copy);
+                assert_eq!(kept(&result), exp_kept, "{:?} vs {:?}", master, copy);
+                assert_eq!(
+                    !result.2.is_empty(),
+                    exp_conflict,
+                    "{:?} vs {:?}",
+                    master,
+                    copy
+                );
+                if exp_conflict {
+                    assert_eq!(result.2[0].kept, 0);
+                    assert!(result.2[0].copy.is_some());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn all_pairs_copy_newest() {
+        for master in all_changes().iter() {
+            for copy in all_changes().iter() {
+                let result = solve(
+                    item(master, FileType::File, OLDER, 10),
+                    item(copy, FileType::File, NEWER, 20),
+                    ConflictPolicy::KeepBoth,
+                );
+                let (exp_kept, exp_conflict) = expected(master, copy);
+                // Changes on both sides go to the newest, removals are unaffected by mtimes
+                let exp_kept = if exp_conflict { Kept::Copy } else { exp_kept };
+                assert_eq!(kept(&result), exp_kept, "{:?} vs {:?}", master, copy);
+                assert_eq!(!result.2.is_empty(), exp_conflict);
+                if exp_conflict {
+                    assert_eq!(result.2[0].kept, 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn all_pairs_identical_contents() {
+        for master in all_changes().iter() {
+            for copy in all_changes().iter() {
+                let result = solve(
+                    item(master, FileType::File, NEWER, 10),
+                    item(copy, FileType::File, NEWER, 10),
+                    ConflictPolicy::KeepBoth,
+                );
+                let (exp_kept, _) = expected(master, copy);
+                // Ties go to master, and identical files are not conflicts
+                assert_eq!(kept(&result), exp_kept, "{:?} vs {:?}", master, copy);
+                assert!(result.2.is_empty(), "{:?} vs {:?}", master, copy);
+            }
+        }
+    }
+
+    #[test]
+    fn hash_decides_when_sizes_match() {
+        let mut master = item(&ChangeType::Newer, FileType::File, NEWER, 10);
+        let mut copy = item(&ChangeType::Newer, FileType::File, OLDER, 10);
+        master.hash = Some(1);
+        copy.hash = Some(1);
+        let result = solve(master.clone(), copy.clone(), ConflictPolicy::KeepBoth);
+        assert_eq!(kept(&result), Kept::Master);
+        assert!(result.2.is_empty());
+        copy.hash = Some(2);
+        let result = solve(master, copy, ConflictPolicy::KeepBoth);
+        assert_eq!(kept(&result), Kept::Master);
+        assert_eq!(result.2.len(), 1);
+    }
+
+    #[test]
+    fn all_pairs_type_changes() {
+        let ftypes = [
+            (FileType::File, FileType::Dir),
+            (FileType::Dir, FileType::File),
+            (FileType::File, FileType::Link),
+            (FileType::Link, FileType::File),
+            (FileType::Dir, FileType::Link),
+            (FileType::Link, FileType::Dir),
+            (FileType::Dir, FileType::Dir),
+            (FileType::Link, FileType::Link),
+        ];
+        for (ftype_master, ftype_copy) in ftypes.iter() {
+            for master in all_changes().iter() {
+                for copy in all_changes().iter() {
+                    let result = solve(
+                        item(master, *ftype_master, NEWER, 10),
+                        item(copy, *ftype_copy, OLDER, 20),
+                        ConflictPolicy::KeepBoth,
+                    );
+                    let (exp_kept, both_changed) = expected(master, copy);
+                    let msg = format!("{:?} {} vs {:?} {}", master, ftype_master, copy, ftype_copy);
+                    if both_changed
+                        && ftype_master != ftype_copy
+                        && *master == ChangeType::NewOnly
+                        && *copy == ChangeType::NewOnly
+                    {
+                        // Neither replaces the other, the conflict is held
+                        assert_eq!(kept(&result), Kept::Neither, "{}", msg);
+                        assert!(result.2[0].is_held(), "{}", msg);
+                        continue;
+                    }
+                    assert_eq!(kept(&result), exp_kept, "{}", msg);
+                    if both_changed && ftype_master != ftype_copy {
+                        // The winner replaces the other, and a losing file is kept as a copy
+                        assert_eq!(result.0, Some(ChangeType::TypeChanged), "{}", msg);
+                        assert_eq!(result.2.len(), (*ftype_copy == FileType::File) as usize);
+                    } else {
+                        assert_eq!(
+                            result.0.as_ref(),
+                            Some(master).filter(|_| exp_kept == Kept::Master),
+                            "{}",
+                            msg
+                        );
+                        assert!(result.2.is_empty(), "{}", msg);
+                    }
+                }
+            }
+        }
+    }
+
+    fn insert_items(
+        diff: &mut HashMap<PathBuf, DiffItem>,
+        items: &[(&str, ChangeType, FileType, i128)],
+    ) {
+        for (path, change, ftype, mtime) in items.iter() {
+            diff.insert(PathBuf::from(path), item(change, *ftype, *mtime, 10));
+        }
+    }
+
+    fn solve_diffs(
+        diff_master: &mut HashMap<PathBuf, DiffItem>,
+        diff_copy: &mut HashMap<PathBuf, DiffItem>,
+    ) -> Vec<Conflict> {
+        solve_conflicts(
+            diff_master,
+            diff_copy,
+            &mut pair(),
+            0,
+            &policies(ConflictPolicy::KeepBoth),
+            &HashMap::new(),
+        )
+        .unwrap()
+    }
+
+    fn change_of(diff: &HashMap<PathBuf, DiffItem>, path: &str) -> Option<ChangeType> {
+        diff.get(&PathBuf::from(path)).map(|d| d.diff.clone())
+    }
+
+    #[test]
+    fn dir_with_changes_wins_over_type_change() {
+        // Master replaced the dir with a newer file, copy edited a file in the dir
+        let mut diff_master = HashMap::new();
+        let mut diff_copy = HashMap::new();
+        insert_items(
+            &mut diff_master,
+            &[
+                ("d", ChangeType::TypeChanged, FileType::File, NEWER),
+                ("d/x", ChangeType::RefOnly, FileType::File, OLDER),
+                ("d/z", ChangeType::RefOnly, FileType::File, OLDER),
+            ],
+        );
+        insert_items(
+            &mut diff_copy,
+            &[
+                ("d", ChangeType::Newer, FileType::Dir, OLDER),
+                ("d/x", ChangeType::Newer, FileType::File, OLDER),
+            ],
+        );
+        let conflicts = solve_diffs(&mut diff_master, &mut diff_copy);
+        assert_eq!(change_of(&diff_copy, "d"), Some(ChangeType::TypeChanged));
+        assert_eq!(change_of(&diff_copy, "d/x"), Some(ChangeType::Newer));
+        assert_eq!(change_of(&diff_master, "d"), None);
+        assert_eq!(change_of(&diff_master, "d/z"), Some(ChangeType::RefOnly));
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kept, 1);
+    }
+
+    #[test]
+    fn new_items_of_different_types_are_held() {
+        let mut diff_master = HashMap::new();
+        let mut diff_copy = HashMap::new();
+        insert_items(
+            &mut diff_master,
+            &[
+                ("d", ChangeType::NewOnly, FileType::Dir, OLDER),
+                ("d/x", ChangeType::NewOnly, FileType::File, OLDER),
+            ],
+        );
+        insert_items(
+            &mut diff_copy,
+            &[("d", ChangeType::NewOnly, FileType::File, NEWER)],
+        );
+        let (mut master, mut copy) = (diff_master.clone(), diff_copy.clone());
+        let conflicts = solve_diffs(&mut master, &mut copy);
+        assert!(master.is_empty());
+        assert!(copy.is_empty());
+        assert!(conflicts[0].is_held());
+        // Until the user picks one
+        let mut decisions = HashMap::new();
+        decisions.insert(PathBuf::from("d"), Decision::Keep(0));
+        let conflicts = solve_conflicts(
+            &mut diff_master,
+            &mut diff_copy,
+            &mut pair(),
+            0,
+            &policies(ConflictPolicy::KeepBoth),
+            &decisions,
+        )
+        .unwrap();
+        assert_eq!(change_of(&diff_master, "d"), Some(ChangeType::TypeChanged));
+        assert_eq!(change_of(&diff_master, "d/x"), Some(ChangeType::NewOnly));
+        assert!(diff_copy.is_empty());
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn type_change_wins_over_unchanged_dir() {
+        // Master replaced the dir with a newer file, copy only removed a file in the dir
+        let mut diff_master = HashMap::new();
+        let mut diff_copy = HashMap::new();
+        insert_items(
+            &mut diff_master,
+            &[
+                ("d", ChangeType::TypeChanged, FileType::File, NEWER),
+                ("d/x", ChangeType::RefOnly, FileType::File, OLDER),
+                ("d/y", ChangeType::RefOnly, FileType::File, OLDER),
+            ],
+        );
+        insert_items(
+            &mut diff_copy,
+            &[
+                ("d", ChangeType::Newer, FileType::Dir, OLDER),
+                ("d/y", ChangeType::RefOnly, FileType::File, OLDER),
+            ],
+        );
+        let conflicts = solve_diffs(&mut diff_master, &mut diff_copy);
+        assert!(diff_copy.is_empty());
+        assert_eq!(change_of(&diff_master, "d"), Some(ChangeType::TypeChanged));
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn dir_replaced_by_file_is_resurrected() {
+        // Master replaced the dir with a file, copy edited a file in the dir without changing the dir
+        let mut diff_master = HashMap::new();
+        let mut diff_copy = HashMap::new();
+        insert_items(
+            &mut diff_master,
+            &[
+                ("d", ChangeType::TypeChanged, FileType::File, NEWER),
+                ("d/x", ChangeType::RefOnly, FileType::File, OLDER),
+                ("d/z", ChangeType::RefOnly, FileType::File, OLDER),
+            ],
+        );
+        insert_items(
+            &mut diff_copy,
+            &[("d/x", ChangeType::Newer, FileType::File, OLDER)],
+        );
+        let conflicts = solve_diffs(&mut diff_master, &mut diff_copy);
+        assert_eq!(diff_master.len(), 1);
+        assert_eq!(change_of(&diff_master, "d/z"), Some(ChangeType::RefOnly));
+        assert_eq!(change_of(&diff_copy, "d"), Some(ChangeType::TypeChanged));
+        assert_eq!(diff_copy[&PathBuf::from("d")].ftype, FileType::Dir);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kept, 1);
+        assert_eq!(conflicts[0].path, PathBuf::from("d"));
+    }
+
+    #[test]
+    fn renamed_counts_as_new() {
+        let renamed = ChangeType::Renamed(PathBuf::from("old.txt"));
+        for copy in all_changes().iter() {
+            let result = solve(
+                item(&renamed, FileType::File, NEWER, 10),
+                item(copy, FileType::File, OLDER, 20),
+                ConflictPolicy::KeepBoth,
+            );
+            let expected_new = expected(&ChangeType::NewOnly, copy);
+            assert_eq!(kept(&result), expected_new.0);
+            assert_eq!(!result.2.is_empty(), expected_new.1);
+        }
+    }
+
+    #[test]
+    fn policies_pick_the_winner() {
+        let master = item(&ChangeType::Newer, FileType::File, NEWER, 10);
+        let copy = item(&ChangeType::Newer, FileType::File, OLDER, 20);
+        let cases = [
+            (ConflictPolicy::Newest, Kept::Master, false),
+            (ConflictPolicy::PreferA, Kept::Master, false),
+            (ConflictPolicy::PreferB, Kept::Copy, false),
+            (ConflictPolicy::Larger, Kept::Copy, false),
+            (ConflictPolicy::KeepBoth, Kept::Master, true),
+            (ConflictPolicy::Skip, Kept::Neither, false),
+            (ConflictPolicy::Interactive, Kept::Neither, false),
+        ];
+        for (policy, exp_kept, exp_copy) in cases.iter() {
+            let result = solve(master.clone(), copy.clone(), *policy);
+            assert_eq!(&kept(&result), exp_kept, "{}", policy);
+            assert_eq!(result.2.len(), 1);
+            assert_eq!(result.2[0].policy, *policy);
+            assert_eq!(result.2[0].copy.is_some(), *exp_copy);
+            assert_eq!(result.2[0].is_held(), *exp_kept == Kept::Neither);
+        }
+    }
+
+    #[test]
+    fn larger_ties_go_to_newest() {
+        let result = solve(
+            item(&ChangeType::Newer, FileType::File, OLDER, 10),
+            item(&ChangeType::Newer, FileType::File, NEWER, 10),
+            ConflictPolicy::Larger,
+        );
+        // Same size but different mtime, without hashes this counts as different contents
+        assert_eq!(kept(&result), Kept::Copy);
+        assert_eq!(result.2.len(), 1);
+    }
+
+    #[test]
+    fn rules_and_decisions() {
+        let rules = vec![
+            (String::from("*.txt"), ConflictPolicy::PreferB),
+            (String::from("*"), ConflictPolicy::PreferA),
+        ];
+        let policies = ConflictPolicies::new(ConflictPolicy::Newest, &rules).unwrap();
+        assert_eq!(
+            policies.for_path(Path::new("dir/file.txt")),
+            ConflictPolicy::PreferB
+        );
+        assert_eq!(
+            policies.for_path(Path::new("file.db")),
+            ConflictPolicy::PreferA
+        );
+        let master = item(&ChangeType::Newer, FileType::File, NEWER, 10);
+        let copy = item(&ChangeType::Newer, FileType::File, OLDER, 20);
+        let result = solve_with(master.clone(), copy.clone(), &policies, &HashMap::new());
+        assert_eq!(kept(&result), Kept::Copy);
+        let mut decisions = HashMap::new();
+        decisions.insert(PathBuf::from("file.txt"), Decision::Keep(0));
+        let result = solve_with(master, copy, &policies, &decisions);
+        assert_eq!(kept(&result), Kept::Master);
+    }
+
+    #[test]
+    fn parse_policies_and_rules() {
+        assert_eq!(
+            ConflictPolicy::parse("skip-and-report"),
+            Ok(ConflictPolicy::Skip)
+        );
+        assert_eq!(
+            ConflictPolicy::parse("Prefer-A"),
+            Ok(ConflictPolicy::PreferA)
+        );
+        assert!(ConflictPolicy::parse("oldest").is_err());
+        assert_eq!(
+            parse_rule("build/**=prefer-b"),
+            Ok((String::from("build/**"), ConflictPolicy::PreferB))
+        );
+        assert!(parse_rule("build/**").is_err());
+        assert!(parse_rule("a[=newest").is_err());
+    }
+
+    #[test]
+    fn removed_dir_with_changes_is_resurrected() {
+        let mut diff_master = HashMap::new();
+        let mut diff_copy = HashMap::new();
+        for path in ["d", "d/e", "d/e/f.txt", "d/g.txt"].iter() {
+            let ftype = if path.ends_with(".txt") {
+                FileType::File
+            } else {
+                FileType::Dir
+            };
+            diff_master.insert(
+                PathBuf::from(path),
+                item(&ChangeType::RefOnly, ftype, OLDER, 10),
+            );
+        }
+        diff_copy.insert(
+            PathBuf::from("d/e/f.txt"),
+            item(&ChangeType::Newer, FileType::File, NEWER, 20),
+        );
+        solve_conflicts(
+            &mut diff_master,
+            &mut diff_copy,
+            &mut pair(),
+            0,
+            &policies(ConflictPolicy::KeepBoth),
+            &HashMap::new(),
+        )
+        .unwrap();
+        // Only the unrelated file is still removed
+        assert_eq!(diff_master.len(), 1);
+        assert!(diff_master.contains_key(&PathBuf::from("d/g.txt")));
+        assert_eq!(diff_copy.len(), 3);
+        for path in ["d", "d/e"].iter() {
+            let diffitem = &diff_copy[&PathBuf::from(path)];
+            assert_eq!(diffitem.diff, ChangeType::NewOnly);
+            assert_eq!(diffitem.ftype, FileType::Dir);
+        }
+        assert_eq!(
+            diff_copy[&PathBuf::from("d/e/f.txt")].diff,
+            ChangeType::Newer
+        );
+    }
+
+    fn merge(diffs: &mut [HashMap<PathBuf, DiffItem>], policy: ConflictPolicy) -> Vec<Conflict> {
+        merge_diffs(diffs, 0, &policies(policy), &HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn removals_in_a_group() {
+        let mut diffs = vec![HashMap::new(), HashMap::new(), HashMap::new()];
+        for diff in diffs.iter_mut().take(2) {
+            insert_items(
+                diff,
+                &[
+                    ("gone", ChangeType::RefOnly, FileType::File, OLDER),
+                    ("partly", ChangeType::RefOnly, FileType::File, OLDER),
+                ],
+            );
+        }
+        insert_items(
+            &mut diffs[2],
+            &[
+                ("gone", ChangeType::RefOnly, FileType::File, OLDER),
+                ("new", ChangeType::NewOnly, FileType::File, NEWER),
+            ],
+        );
+        let conflicts = merge(&mut diffs, ConflictPolicy::KeepBoth);
+        assert!(conflicts.is_empty());
+        // Removed everywhere, nothing left to do
+        assert_eq!(change_of(&diffs[0], "gone"), None);
+        // Removed from C by the first directory that removed it
+        assert_eq!(change_of(&diffs[0], "partly"), Some(ChangeType::RefOnly));
+        assert_eq!(change_of(&diffs[1], "partly"), None);
+        assert_eq!(change_of(&diffs[2], "new"), Some(ChangeType::NewOnly));
+        assert_eq!(diffs[1].len(), 0);
+    }
+
+    #[test]
+    fn conflicts_in_a_group() {
+        let mut diffs = vec![HashMap::new(), HashMap::new(), HashMap::new()];
+        insert_items(
+            &mut diffs[0],
+            &[("file.txt", ChangeType::Newer, FileType::File, OLDER)],
+        );
+        insert_items(
+            &mut diffs[2],
+            &[("file.txt", ChangeType::Newer, FileType::File, NEWER)],
+        );
+        let conflicts = merge(&mut diffs, ConflictPolicy::KeepBoth);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!((conflicts[0].kept, conflicts[0].lost), (2, 0));
+        assert!(conflicts[0]
+            .copy
+            .as_ref()
+            .unwrap()
+            .to_string_lossy()
+            .contains("conflict-A-"));
+        assert_eq!(change_of(&diffs[0], "file.txt"), None);
+        assert_eq!(change_of(&diffs[2], "file.txt"), Some(ChangeType::Newer));
+
+        // A skipped conflict stays unchanged, also in the directories that changed it later
+        for (idx, diff) in diffs.iter_mut().enumerate() {
+            diff.clear();
+            diff.insert(
+                PathBuf::from("file.txt"),
+                item(&ChangeType::Newer, FileType::File, NEWER, 10 * idx as u64),
+            );
+        }
+        let conflicts = merge(&mut diffs, ConflictPolicy::Skip);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].sides(), (0, 1));
+        assert!(diffs.iter().all(|diff| diff.is_empty()));
+    }
+
+    #[test]
+    fn decision_in_a_group() {
+        let changed = |diffs: &mut Vec<HashMap<PathBuf, DiffItem>>| {
+            for (idx, diff) in diffs.iter_mut().enumerate() {
+                diff.clear();
+                diff.insert(
+                    PathBuf::from("file.txt"),
+                    item(&ChangeType::Newer, FileType::File, NEWER, 10 * idx as u64),
+                );
+            }
+        };
+        let mut diffs = vec![HashMap::new(), HashMap::new(), HashMap::new()];
+        changed(&mut diffs);
+        let conflicts = merge(&mut diffs, ConflictPolicy::Interactive);
+        assert_eq!(conflicts[0].sides(), (0, 1));
+        // B is chosen, and wins over C too
+        let mut decisions = HashMap::new();
+        decisions.insert(PathBuf::from("file.txt"), Decision::Keep(1));
+        changed(&mut diffs);
+        let policies = policies(ConflictPolicy::Interactive);
+        let conflicts = merge_diffs(&mut diffs, 0, &policies, &decisions).unwrap();
+        assert!(conflicts.iter().all(|conflict| conflict.kept == 1));
+        assert_eq!(change_of(&diffs[1], "file.txt"), Some(ChangeType::Newer));
+        assert_eq!(change_of(&diffs[0], "file.txt"), None);
+        assert_eq!(change_of(&diffs[2], "file.txt"), None);
+        // C wasn't shown, the decision doesn't say anything about A and B
+        decisions.insert(PathBuf::from("file.txt"), Decision::Keep(2));
+        changed(&mut diffs);
+        let conflicts = merge_diffs(&mut diffs, 0, &policies, &decisions).unwrap();
+        assert!(conflicts[0].is_held());
+        assert!(diffs.iter().all(|diff| diff.is_empty()));
+    }
+
+    #[test]
+    fn conflict_names() {
+        assert_eq!(
+            conflict_name(&PathBuf::from("dir/report.txt"), "B", "20191024-153012"),
+            PathBuf::from("dir/report.conflict-B-20191024-153012.txt")
+        );
+        assert_eq!(
+            conflict_name(&PathBuf::from("Makefile"), "A", "20191024-153012"),
+            PathBuf::from("Makefile.conflict-A-20191024-153012")
+        );
+    }
+}
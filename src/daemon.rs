@@ -0,0 +1,273 @@
+use crate::conflicts::Conflict;
+use crate::retry::RetryQueue;
+use crate::{Command, Worker};
+use chrono::{Local, TimeZone};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+pub const CTL_COMMANDS: [&str; 7] = [
+    "status",
+    "sync",
+    "pause",
+    "resume",
+    "quit",
+    "confirm-deletions",
+    "abandon-retries",
+];
+
+/// The socket is placed in the runtime dir of the user, or in /tmp if there is none.
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("twowaysync.sock"),
+        None => PathBuf::from(format!("/tmp/twowaysync-{}.sock", unsafe {
+            libc::getuid()
+        })),
+    }
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct PendingAction {
+    pub path: PathBuf,
+    pub action: String,
+    pub error: String,
+    pub attempts: u32,
+    pub next_attempt: String,
+}
+
+/// State of the watch loop of a pair or group, as reported by the status command.
+#[derive(Clone, Default, Serialize)]
+pub struct SyncStatus {
+    pub name: String,
+    pub state: String,
+    pub dirs: Vec<PathBuf>,
+    pub last_sync: Option<String>,
+    pub last_sync_ok: Option<bool>,
+    pub errors: Vec<String>,
+    pub pending_actions: Vec<PendingAction>,
+    pub conflicts: Vec<String>,
+    pub settling: Vec<PathBuf>,
+    pub deletions_paused: bool,
+}
+
+fn format_time(secs: u64) -> String {
+    match Local.timestamp_opt(secs as i64, 0) {
+        chrono::LocalResult::Single(time) => time.to_rfc3339(),
+        _ => secs.to_string(),
+    }
+}
+
+impl SyncStatus {
+    pub fn new(name: &str, dirs: &[PathBuf]) -> SyncStatus {
+        SyncStatus {
+            name: name.to_string(),
+            state: String::from("starting"),
+            dirs: dirs.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// Record the outcome of a sync.
+    pub fn synced(
+        &mut self,
+        errors: &[String],
+        retries: &RetryQueue,
+        conflicts: &[Conflict],
+        settling: &HashSet<PathBuf>,
+        deletions_paused: bool,
+    ) {
+        self.last_sync = Some(Local::now().to_rfc3339());
+        self.last_sync_ok = Some(errors.is_empty());
+        self.errors = errors.to_vec();
+        self.pending_actions = retries
+            .items
+            .iter()
+            .map(|(path, item)| PendingAction {
+                path: path.to_path_buf(),
+                action: item.action.to_string(),
+                error: item.error.clone(),
+                attempts: item.attempts,
+                next_attempt: format_time(item.next_attempt),
+            })
+            .collect();
+        self.pending_actions.sort_by(|a, b| a.path.cmp(&b.path));
+        self.conflicts = conflicts.iter().map(|c| c.to_string()).collect();
+        self.settling = settling.iter().cloned().collect();
+        self.settling.sort();
+        self.deletions_paused = deletions_paused;
+    }
+}
+
+fn reply_ok() -> String {
+    String::from("{\"ok\":true}")
+}
+
+fn reply_error(message: &str) -> String {
+    serde_json::json!({ "ok": false, "error": message }).to_string()
+}
+
+fn parse_command(name: &str) -> Option<Command> {
+    match name {
+        "sync" => Some(Command::SyncNow),
+        "pause" => Some(Command::Pause),
+        "resume" => Some(Command::Resume),
+        "quit" => Some(Command::SyncAndExit),
+        "confirm-deletions" => Some(Command::ConfirmDeletions),
+        "abandon-retries" => Some(Command::AbandonRetries),
+        _ => None,
+    }
+}
+
+// The pairs a command is for, all of them if no name is given
+fn targets<'a>(workers: &'a [Worker], name: Option<&str>) -> Result<Vec<&'a Worker>, String> {
+    match name {
+        Some(name) => match workers.iter().find(|worker| worker.name == name) {
+            Some(worker) => Ok(vec![worker]),
+            None => Err(format!("no pair named '{}'", name)),
+        },
+        None => Ok(workers.iter().collect()),
+    }
+}
+
+fn run_command(line: &str, workers: &[Worker]) -> Result<String, Box<dyn Error>> {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let targets = match targets(workers, words.next()) {
+        Ok(targets) => targets,
+        Err(e) => return Ok(reply_error(&e)),
+    };
+    if name == "status" {
+        let statuses = targets
+            .iter()
+            .map(|worker| worker.status.lock().unwrap().clone())
+            .collect::<Vec<SyncStatus>>();
+        return Ok(serde_json::to_string_pretty(&statuses)?);
+    }
+    let mut sent = false;
+    for worker in targets.iter() {
+        match parse_command(name) {
+            Some(command) => sent |= worker.tx.send(command).is_ok(),
+            None => return Ok(reply_error(&format!("unknown command '{}'", name))),
+        }
+    }
+    if sent {
+        Ok(reply_ok())
+    } else {
+        Ok(reply_error("the sync loop has stopped"))
+    }
+}
+
+// Each connection sends one command on a line, optionally followed by the name of a pair,
+// and gets one reply
+fn handle_client(stream: UnixStream, workers: &[Worker]) -> Result<(), Box<dyn Error>> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut line = String::new();
+    // Nothing is sent when another daemon checks if this one is alive
+    if BufReader::new(&stream).read_line(&mut line)? == 0 {
+        return Ok(());
+    }
+    let reply = run_command(&line, workers)?;
+    (&stream).write_all(reply.as_bytes())?;
+    (&stream).write_all(b"\n")?;
+    Ok(())
+}
+
+/// Create the control socket. A stale socket left by a daemon that died is replaced,
+/// but not the socket of one that is still running.
+pub fn bind_socket(path: &PathBuf) -> Result<UnixListener, Box<dyn Error>> {
+    if fs::symlink_metadata(path).is_ok() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(From::from(format!(
+                "another daemon is listening on {}",
+                path.display()
+            )));
+        }
+        fs::remove_file(path)?;
+    }
+    // The socket must never be reachable by others, not even between bind and chmod.
+    // This runs before the sync threads are started, so the umask affects nothing else.
+    let old_mask = unsafe { libc::umask(0o177) };
+    let bound = UnixListener::bind(path);
+    unsafe { libc::umask(old_mask) };
+    let listener = bound?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Serve control commands on a Unix socket until the sync loops of all pairs have finished.
+/// A stop request makes the loops finish the current sync and exit, like the quit command.
+/// Each connection is handled in its own thread, so a client that doesn't send anything
+/// can't keep the others waiting.
+pub fn serve(
+    listener: UnixListener,
+    socket_path: &PathBuf,
+    workers: &[Worker],
+    stop_requested: &AtomicBool,
+) -> Result<(), Box<dyn Error>> {
+    let mut stopping = false;
+    let result = thread::scope(|scope| {
+        while !workers.iter().all(|worker| worker.handle.is_finished()) {
+            if !stopping && stop_requested.load(Ordering::SeqCst) {
+                println!("Exiting after next sync...");
+                for worker in workers.iter() {
+                    // Fails only if the worker already stopped
+                    let _res = worker.tx.send(Command::SyncAndExit);
+                }
+                stopping = true;
+            }
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    scope.spawn(move || {
+                        if let Err(e) = handle_client(stream, workers) {
+                            println!("Control connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(200));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    });
+    let _res = fs::remove_file(socket_path);
+    Ok(result?)
+}
+
+/// Send a command to a running daemon and return its reply.
+/// A reply that reports a failure is returned as an error.
+pub fn send_command(
+    socket_path: &PathBuf,
+    command: &str,
+    pair: Option<&str>,
+) -> Result<String, Box<dyn Error>> {
+    let mut stream = UnixStream::connect(socket_path)
+        .map_err(|e| format!("unable to connect to {}: {}", socket_path.display(), e))?;
+    stream.write_all(command.as_bytes())?;
+    if let Some(pair) = pair {
+        stream.write_all(b" ")?;
+        stream.write_all(pair.as_bytes())?;
+    }
+    stream.write_all(b"\n")?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    let parsed: serde_json::Value = serde_json::from_str(&reply)?;
+    match parsed.get("error") {
+        Some(error) => Err(From::from(
+            error.as_str().unwrap_or("unknown error").to_string(),
+        )),
+        None => Ok(reply),
+    }
+}
@@ -1,3 +1,4 @@
+use crate::trash::Trash;
 use filetime::FileTime;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
@@ -5,7 +6,14 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh3::xxh3_64;
+
+pub const TEMPPREFIX: &str = ".twoway.tmp.";
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum ChangeType {
@@ -14,23 +22,40 @@ pub enum ChangeType {
     NewOnly,
     RefOnly,
     Modified,
+    Renamed(PathBuf),
+    TypeChanged,
 }
 
 #[derive(Clone, Debug)]
 pub struct DiffItem {
     pub diff: ChangeType,
     pub ftype: FileType,
-    pub mtime: i64,
+    pub mtime: i128,
+    pub size: u64,
+    pub hash: Option<u64>,
 }
 
 impl DiffItem {
-    pub fn new(diff: ChangeType, ftype: FileType, mtime: i64) -> DiffItem {
+    pub fn new(diff: ChangeType, pathdata: &PathData) -> DiffItem {
         DiffItem {
-            diff: diff,
-            ftype: ftype,
-            mtime: mtime,
+            diff,
+            ftype: pathdata.ftype,
+            mtime: pathdata.mtime_ns(),
+            size: pathdata.size,
+            hash: pathdata.hash,
         }
     }
+
+    /// True if both are files that may have different contents.
+    pub fn differs_from(&self, other: &DiffItem, mtime_tolerance: i128) -> bool {
+        self.ftype == FileType::File
+            && other.ftype == FileType::File
+            && (self.size != other.size
+                || match (self.hash, other.hash) {
+                    (Some(hash), Some(other_hash)) => hash != other_hash,
+                    _ => compare_mtime(self.mtime, other.mtime, mtime_tolerance) != Ordering::Equal,
+                })
+    }
 }
 
 impl fmt::Display for ChangeType {
@@ -41,13 +66,22 @@ impl fmt::Display for ChangeType {
             ChangeType::NewOnly => write!(f, "Added"),
             ChangeType::RefOnly => write!(f, "Removed"),
             ChangeType::Modified => write!(f, "Modified"),
+            ChangeType::Renamed(from) => write!(f, "Renamed from {}", from.display()),
+            ChangeType::TypeChanged => write!(f, "Type changed"),
         }
     }
 }
 
 impl fmt::Display for DiffItem {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} {}, mtime: {}", self.diff, self.ftype, self.mtime)
+        write!(
+            f,
+            "{} {}, mtime: {}.{:09}",
+            self.diff,
+            self.ftype,
+            self.mtime.div_euclid(1_000_000_000),
+            self.mtime.rem_euclid(1_000_000_000)
+        )
     }
 }
 
@@ -71,30 +105,135 @@ impl fmt::Display for FileType {
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PathData {
     pub mtime: i64,
+    #[serde(default)]
+    pub mtime_nsec: u32,
     pub perms: u32,
     pub size: u64,
     pub ftype: FileType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<u64>,
+    #[serde(default)]
+    pub ino: u64,
+    // Only stored for directories
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub ctime: i64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub ctime_nsec: u32,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+fn is_zero<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Compare two mtimes in nanoseconds, treating them as equal if
+/// they differ by no more than the tolerance.
+pub fn compare_mtime(a: i128, b: i128, tolerance: i128) -> Ordering {
+    if (a - b).abs() <= tolerance {
+        Ordering::Equal
+    } else {
+        a.cmp(&b)
+    }
+}
+
+impl PathData {
+    pub fn mtime_ns(&self) -> i128 {
+        self.mtime as i128 * 1_000_000_000 + self.mtime_nsec as i128
+    }
+
+    pub fn ctime_ns(&self) -> i128 {
+        self.ctime as i128 * 1_000_000_000 + self.ctime_nsec as i128
+    }
+
+    /// True if everything except the mtime is equal.
+    pub fn same_attributes(&self, other: &PathData) -> bool {
+        self.perms == other.perms
+            && self.size == other.size
+            && self.ftype == other.ftype
+            && (self.hash.is_none() || other.hash.is_none() || self.hash == other.hash)
+    }
+
+    /// True if both are files that look identical apart from the name,
+    /// with the same size, mtime and (if available) hash.
+    pub fn same_file(&self, other: &PathData) -> bool {
+        self.ftype == FileType::File
+            && other.ftype == FileType::File
+            && self.size == other.size
+            && self.mtime_ns() == other.mtime_ns()
+            && (self.hash.is_none() || other.hash.is_none() || self.hash == other.hash)
+    }
+
+    /// True if both are files with equal size and hash, regardless of mtime.
+    /// Always false if either one hasn't been hashed.
+    pub fn same_content(&self, other: &PathData) -> bool {
+        self.ftype == FileType::File
+            && other.ftype == FileType::File
+            && self.size == other.size
+            && self.hash.is_some()
+            && self.hash == other.hash
+    }
+}
+
+// Version 1 added nanoseconds to the mtimes
+pub const INDEX_VERSION: u32 = 1;
+
+/// A synced directory, with the inode it had when the index was made, and the UUID of
+/// its file system if it has one. The root of a file system has the same inode number as
+/// the root of any other file system of the same type, so a mount point is only told
+/// apart by the UUID. The device number isn't used, it changes when a network share or
+/// a USB drive is mounted again.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RootId {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub filesystem: Option<String>,
+    pub inode: u64,
+}
+
+/// Identifies the pair or group an index was made for.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PairIdentity {
+    pub id: String,
+    pub roots: Vec<RootId>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct DirIndex {
+    #[serde(default)]
+    pub version: u32,
     pub scantime: u64,
     pub root: PathBuf,
+    #[serde(default)]
+    pub pair: Option<PairIdentity>,
     pub contents: HashMap<PathBuf, PathData>,
 }
 
+impl DirIndex {
+    /// Upgrade an index saved by an older version, using a fresh scan.
+    /// Old indexes only have whole seconds, the nanoseconds are taken from
+    /// the scan for every entry that is otherwise unchanged.
+    pub fn upgrade(&mut self, scan: &DirIndex) {
+        if self.version < 1 {
+            for (path, pathdata) in self.contents.iter_mut() {
+                if let Some(current) = scan.contents.get(path) {
+                    if current.mtime == pathdata.mtime && current.same_attributes(pathdata) {
+                        pathdata.mtime_nsec = current.mtime_nsec;
+                    }
+                }
+            }
+        }
+        self.version = INDEX_VERSION;
+    }
+}
+
 impl PartialEq for PathData {
     fn eq(&self, other: &PathData) -> bool {
-        self.mtime == other.mtime
-            && self.perms == other.perms
-            && self.size == other.size
-            && self.ftype == other.ftype
+        self.mtime_ns() == other.mtime_ns() && self.same_attributes(other)
     }
 }
 
 impl Eq for PathData {}
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum SyncAction {
     CopyFile { src: PathBuf, dest: PathBuf },
     CopyDir { src: PathBuf, dest: PathBuf },
@@ -102,21 +241,34 @@ pub enum SyncAction {
     CopyMeta { src: PathBuf, dest: PathBuf },
     DeleteFile { dest: PathBuf },
     DeleteDir { dest: PathBuf },
+    Rename { src: PathBuf, dest: PathBuf },
+    Remove { dest: PathBuf },
 }
 
-pub trait Prio {
-    fn prio(&self) -> usize;
-}
+impl SyncAction {
+    pub fn dest(&self) -> &PathBuf {
+        match self {
+            SyncAction::CopyFile { dest, .. }
+            | SyncAction::CopyDir { dest, .. }
+            | SyncAction::CopyLink { dest, .. }
+            | SyncAction::CopyMeta { dest, .. }
+            | SyncAction::DeleteFile { dest }
+            | SyncAction::DeleteDir { dest }
+            | SyncAction::Rename { dest, .. }
+            | SyncAction::Remove { dest } => dest,
+        }
+    }
 
-impl Prio for SyncAction {
-    fn prio(&self) -> usize {
+    pub fn src(&self) -> Option<&PathBuf> {
         match self {
-            &SyncAction::CopyFile { src: _, dest: _ } => 2,
-            &SyncAction::CopyDir { src: _, dest: _ } => 1,
-            &SyncAction::CopyLink { src: _, dest: _ } => 4,
-            &SyncAction::CopyMeta { src: _, dest: _ } => 7,
-            &SyncAction::DeleteFile { dest: _ } => 5,
-            &SyncAction::DeleteDir { dest: _ } => 6,
+            SyncAction::CopyFile { src, .. }
+            | SyncAction::CopyDir { src, .. }
+            | SyncAction::CopyLink { src, .. }
+            | SyncAction::CopyMeta { src, .. }
+            | SyncAction::Rename { src, .. } => Some(src),
+            SyncAction::DeleteFile { .. }
+            | SyncAction::DeleteDir { .. }
+            | SyncAction::Remove { .. } => None,
         }
     }
 }
@@ -163,63 +315,17 @@ impl PartialEq for SyncAction {
                     src: ref src_b,
                     dest: ref dest_b,
                 },
-            ) => src_a == src_b && dest_a == dest_b,
-            (
-                &SyncAction::DeleteFile { dest: ref dest_a },
-                &SyncAction::DeleteFile { dest: ref dest_b },
-            )
-            | (
-                &SyncAction::DeleteDir { dest: ref dest_a },
-                &SyncAction::DeleteDir { dest: ref dest_b },
-            ) => (dest_a == dest_b),
-            _ => false,
-        }
-    }
-}
-
-impl Ord for SyncAction {
-    fn cmp(&self, other: &SyncAction) -> Ordering {
-        match (self, other) {
-            (
-                &SyncAction::CopyFile {
-                    src: ref src_a,
-                    dest: _,
-                },
-                &SyncAction::CopyFile {
-                    src: ref src_b,
-                    dest: _,
-                },
-            )
-            | (
-                &SyncAction::CopyLink {
-                    src: ref src_a,
-                    dest: _,
-                },
-                &SyncAction::CopyLink {
-                    src: ref src_b,
-                    dest: _,
-                },
             )
             | (
-                &SyncAction::CopyDir {
+                &SyncAction::Rename {
                     src: ref src_a,
-                    dest: _,
-                },
-                &SyncAction::CopyDir {
-                    src: ref src_b,
-                    dest: _,
-                },
-            ) => src_a.iter().count().cmp(&src_b.iter().count()),
-            (
-                &SyncAction::CopyMeta {
-                    src: ref src_a,
-                    dest: _,
+                    dest: ref dest_a,
                 },
-                &SyncAction::CopyMeta {
+                &SyncAction::Rename {
                     src: ref src_b,
-                    dest: _,
+                    dest: ref dest_b,
                 },
-            ) => src_b.iter().count().cmp(&src_a.iter().count()),
+            ) => src_a == src_b && dest_a == dest_b,
             (
                 &SyncAction::DeleteFile { dest: ref dest_a },
                 &SyncAction::DeleteFile { dest: ref dest_b },
@@ -227,20 +333,18 @@ impl Ord for SyncAction {
             | (
                 &SyncAction::DeleteDir { dest: ref dest_a },
                 &SyncAction::DeleteDir { dest: ref dest_b },
-            ) => dest_b.iter().count().cmp(&dest_a.iter().count()),
-            _ => self.prio().cmp(&other.prio()),
+            )
+            | (
+                &SyncAction::Remove { dest: ref dest_a },
+                &SyncAction::Remove { dest: ref dest_b },
+            ) => dest_a == dest_b,
+            _ => false,
         }
     }
 }
 
 impl Eq for SyncAction {}
 
-impl PartialOrd for SyncAction {
-    fn partial_cmp(&self, other: &SyncAction) -> Option<Ordering> {
-        Some(self.cmp(&other))
-    }
-}
-
 impl fmt::Display for SyncAction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -250,71 +354,163 @@ impl fmt::Display for SyncAction {
             SyncAction::CopyLink { src, dest: _ } => write!(f, "CopyLink: {}", src.display()),
             SyncAction::DeleteFile { dest } => write!(f, "DeleteFile: {}", dest.display()),
             SyncAction::DeleteDir { dest } => write!(f, "DeleteDir: {}", dest.display()),
+            SyncAction::Rename { src, dest } => {
+                write!(f, "Rename: {} to {}", src.display(), dest.display())
+            }
+            SyncAction::Remove { dest } => write!(f, "Remove: {}", dest.display()),
         }
     }
 }
 
+fn make_writable(path: &PathBuf) -> Result<(), Box<dyn Error>> {
+    let mut perms = fs::metadata(path)?.permissions();
+    if perms.readonly() {
+        perms.set_mode(perms.mode() | 0o200);
+        fs::set_permissions(path, perms)?;
+    }
+    Ok(())
+}
+
+pub fn is_tempfile(path: &Path) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.starts_with(TEMPPREFIX),
+        None => false,
+    }
+}
+
+// Most file systems allow names of at most 255 bytes
+const MAX_NAME_LEN: usize = 255;
+
+// The temporary file is named after the destination, unless the name would get too long.
+// Then a hash of the name is used instead.
+fn temp_path(dest: &Path) -> PathBuf {
+    let filename = dest.file_name().unwrap_or_default();
+    let mut name = std::ffi::OsString::from(TEMPPREFIX);
+    if TEMPPREFIX.len() + filename.len() <= MAX_NAME_LEN {
+        name.push(filename);
+    } else {
+        name.push(format!("{:016x}", xxh3_64(filename.as_bytes())));
+    }
+    dest.with_file_name(name)
+}
+
+// Write the contents and metadata of src to tmp, and flush it to disk
+fn copy_to_temp(src: &PathBuf, tmp: &PathBuf) -> Result<(), Box<dyn Error>> {
+    let mut infile = File::open(src)?;
+    let mut outfile = OpenOptions::new().write(true).create_new(true).open(tmp)?;
+    io::copy(&mut infile, &mut outfile)?;
+    outfile.sync_all()?;
+    let attr = infile.metadata()?;
+    fs::set_permissions(tmp, attr.permissions())?;
+    let mtime = FileTime::from_last_modification_time(&attr);
+    let atime = FileTime::from_last_access_time(&attr);
+    filetime::set_file_times(tmp, atime, mtime)?;
+    Ok(())
+}
+
+// Copy via a temporary file in the destination dir that is renamed into place,
+// so that an interrupted copy never leaves a truncated file behind.
+fn copy_file_atomic(
+    src: &PathBuf,
+    dest: &PathBuf,
+    trash: Option<&Trash>,
+) -> Result<(), Box<dyn Error>> {
+    let tmp = temp_path(dest);
+    if fs::symlink_metadata(&tmp).is_ok() {
+        fs::remove_file(&tmp)?;
+    }
+    if let Err(e) = copy_to_temp(src, &tmp) {
+        let _res = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    if let Some(trash) = trash {
+        if fs::symlink_metadata(dest)
+            .map(|m| m.is_file())
+            .unwrap_or(false)
+        {
+            if let Err(e) = trash.keep_version(dest) {
+                let _res = fs::remove_file(&tmp);
+                return Err(e);
+            }
+        }
+    }
+    if let Err(e) = fs::rename(&tmp, dest) {
+        let _res = fs::remove_file(&tmp);
+        return Err(Box::new(e));
+    }
+    if let Some(parent) = dest.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
 pub trait RunAction {
-    fn run(&self) -> Result<(), Box<dyn Error>>;
+    fn run(&self, trash: Option<&Trash>) -> Result<(), Box<dyn Error>>;
 }
 
 impl RunAction for SyncAction {
-    fn run(&self) -> Result<(), Box<dyn Error>> {
+    fn run(&self, trash: Option<&Trash>) -> Result<(), Box<dyn Error>> {
         match self {
             SyncAction::CopyFile { src, dest } => {
-                if fs::metadata(&dest).is_ok() {
-                    let mut perms = fs::metadata(&dest)?.permissions();
-                    let readonly = perms.readonly();
-                    if readonly {
-                        perms.set_readonly(false);
-                        fs::set_permissions(&dest, perms)?;
-                    }
-                }
-                let _bytescopied = fs::copy(&src, &dest)?;
+                copy_file_atomic(src, dest, trash)?;
                 Ok(())
             }
             SyncAction::CopyDir { src: _, dest } => {
-                if !fs::metadata(&dest).is_ok() {
-                    fs::create_dir(&dest)?;
+                if fs::metadata(dest).is_err() {
+                    fs::create_dir(dest)?;
                 }
                 Ok(())
             }
             SyncAction::CopyMeta { src, dest } => {
-                let perms = fs::metadata(&src)?.permissions();
-                fs::set_permissions(&dest, perms)?;
-                let attr = fs::metadata(&src)?;
+                let perms = fs::metadata(src)?.permissions();
+                fs::set_permissions(dest, perms)?;
+                let attr = fs::metadata(src)?;
                 let mtime = FileTime::from_last_modification_time(&attr);
                 let atime = FileTime::from_last_access_time(&attr);
-                let _res = filetime::set_file_times(&dest, atime, mtime);
+                let _res = filetime::set_file_times(dest, atime, mtime);
                 Ok(())
             }
             SyncAction::CopyLink { src, dest } => {
                 //let attr = fs::symlink_metadata(src)?;
                 let target = fs::read_link(src)?;
                 if fs::symlink_metadata(dest).is_ok() {
-                    fs::remove_file(&dest)?;
+                    fs::remove_file(dest)?;
                 }
                 std::os::unix::fs::symlink(target, dest)?;
                 Ok(())
             }
             SyncAction::DeleteFile { dest } => {
-                let mut perms = fs::metadata(&dest)?.permissions();
-                let readonly = perms.readonly();
-                if readonly {
-                    perms.set_readonly(false);
-                    fs::set_permissions(&dest, perms)?;
+                match trash {
+                    Some(trash) => trash.stash(dest)?,
+                    None => {
+                        make_writable(dest)?;
+                        fs::remove_file(dest)?;
+                    }
                 }
-                fs::remove_file(&dest)?;
                 Ok(())
             }
             SyncAction::DeleteDir { dest } => {
-                let mut perms = fs::metadata(&dest)?.permissions();
-                let readonly = perms.readonly();
-                if readonly {
-                    perms.set_readonly(false);
-                    fs::set_permissions(&dest, perms)?;
+                make_writable(dest)?;
+                fs::remove_dir(dest)?;
+                Ok(())
+            }
+            SyncAction::Rename { src, dest } => {
+                if fs::symlink_metadata(dest).is_ok() {
+                    return Err(From::from(format!("{} already exists", dest.display())));
+                }
+                fs::rename(src, dest)?;
+                Ok(())
+            }
+            SyncAction::Remove { dest } => {
+                let meta = match fs::symlink_metadata(dest) {
+                    Ok(meta) => meta,
+                    Err(_) => return Ok(()),
+                };
+                match trash {
+                    Some(trash) => trash.stash(dest)?,
+                    None if meta.is_dir() => fs::remove_dir_all(dest)?,
+                    None => fs::remove_file(dest)?,
                 }
-                fs::remove_dir(&dest)?;
                 Ok(())
             }
         }
@@ -0,0 +1,312 @@
+use crate::datatypes::{RunAction, SyncAction};
+use crate::pool::Pool;
+use crate::trash::Trash;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+// Actions run in three phases. Everything is first created or replaced, then removed
+// items are deleted, and last the metadata is copied, so that no directory is modified
+// after its mtime was set.
+fn phase(action: &SyncAction) -> usize {
+    match action {
+        SyncAction::Remove { .. }
+        | SyncAction::Rename { .. }
+        | SyncAction::CopyDir { .. }
+        | SyncAction::CopyFile { .. }
+        | SyncAction::CopyLink { .. } => 0,
+        SyncAction::DeleteFile { .. } | SyncAction::DeleteDir { .. } => 1,
+        SyncAction::CopyMeta { .. } => 2,
+    }
+}
+
+/// For every action, the indexes of the actions that must finish before it can start.
+/// - Nothing is done at or below a path until the entry there was removed, or renamed away.
+/// - Nothing is done below a path until the directory there was created, or renamed into place.
+/// - Directories are deleted, and get their metadata, after everything inside them.
+fn dependencies(actions: &[SyncAction]) -> Vec<Vec<usize>> {
+    let mut creators: HashMap<&Path, Vec<usize>> = HashMap::new();
+    let mut removers: HashMap<&Path, Vec<usize>> = HashMap::new();
+    let mut contents: HashMap<&Path, Vec<usize>> = HashMap::new();
+    for (idx, action) in actions.iter().enumerate() {
+        match action {
+            SyncAction::CopyDir { dest, .. } => creators.entry(dest).or_default().push(idx),
+            SyncAction::Rename { src, dest } => {
+                creators.entry(dest).or_default().push(idx);
+                removers.entry(src).or_default().push(idx);
+            }
+            SyncAction::Remove { dest } => removers.entry(dest).or_default().push(idx),
+            _ => {}
+        }
+        let mut paths = vec![action.dest()];
+        paths.extend(
+            action
+                .src()
+                .filter(|_| matches!(action, SyncAction::Rename { .. })),
+        );
+        for path in paths {
+            for parent in path.ancestors().skip(1) {
+                contents.entry(parent).or_default().push(idx);
+            }
+        }
+    }
+    actions
+        .iter()
+        .enumerate()
+        .map(|(idx, action)| {
+            let dest = action.dest();
+            let mut deps = Vec::new();
+            if let SyncAction::DeleteDir { .. } | SyncAction::CopyMeta { .. } = action {
+                deps.extend(contents.get(dest.as_path()).into_iter().flatten());
+            }
+            for path in dest.ancestors() {
+                deps.extend(removers.get(path).into_iter().flatten());
+                if path != dest {
+                    deps.extend(creators.get(path).into_iter().flatten());
+                }
+            }
+            deps.retain(|dep| *dep != idx);
+            deps.sort_unstable();
+            deps.dedup();
+            deps
+        })
+        .collect()
+}
+
+struct Schedule {
+    ready: VecDeque<usize>,
+    waiting_for: Vec<usize>,
+    started: Vec<bool>,
+    running: usize,
+    finished: usize,
+}
+
+fn run_action(action: &SyncAction, trash: Option<&Trash>) -> Option<String> {
+    println!("{}\r", action);
+    match action.run(trash) {
+        Ok(_) => None,
+        Err(e) => {
+            println!("Action run error {}, {}\r", e, action);
+            Some(e.to_string())
+        }
+    }
+}
+
+fn run_phase(
+    actions: Vec<SyncAction>,
+    trash: Option<&Trash>,
+    threads: usize,
+    pool: &Pool,
+) -> Vec<(SyncAction, String)> {
+    let deps = dependencies(&actions);
+    let mut dependents = vec![Vec::new(); actions.len()];
+    for (idx, action_deps) in deps.iter().enumerate() {
+        for dep in action_deps.iter() {
+            dependents[*dep].push(idx);
+        }
+    }
+    let schedule = Mutex::new(Schedule {
+        ready: (0..actions.len())
+            .filter(|idx| deps[*idx].is_empty())
+            .collect(),
+        waiting_for: deps.iter().map(|d| d.len()).collect(),
+        started: vec![false; actions.len()],
+        running: 0,
+        finished: 0,
+    });
+    let wakeup = Condvar::new();
+    let errors = Mutex::new(Vec::new());
+    thread::scope(|s| {
+        for _ in 0..threads.clamp(1, actions.len().max(1)) {
+            s.spawn(|| loop {
+                let idx = {
+                    let mut sched = schedule.lock().unwrap();
+                    loop {
+                        if let Some(idx) = sched.ready.pop_front() {
+                            if !sched.started[idx] {
+                                sched.started[idx] = true;
+                                sched.running += 1;
+                                break idx;
+                            }
+                            continue;
+                        }
+                        if sched.finished == actions.len() {
+                            return;
+                        }
+                        if sched.running == 0 {
+                            // Circular dependencies, for example two items swapping names.
+                            // Run the first remaining action and let it fail if it must.
+                            let next = sched.started.iter().position(|started| !started).unwrap();
+                            sched.ready.push_back(next);
+                            continue;
+                        }
+                        sched = wakeup.wait(sched).unwrap();
+                    }
+                };
+                let result = {
+                    let _permit = pool.acquire();
+                    run_action(&actions[idx], trash)
+                };
+                if let Some(error) = result {
+                    errors.lock().unwrap().push((idx, error));
+                }
+                let mut sched = schedule.lock().unwrap();
+                sched.running -= 1;
+                sched.finished += 1;
+                for dependent in dependents[idx].iter() {
+                    sched.waiting_for[*dependent] -= 1;
+                    if sched.waiting_for[*dependent] == 0 {
+                        sched.ready.push_back(*dependent);
+                    }
+                }
+                wakeup.notify_all();
+            });
+        }
+    });
+    let mut errors = errors.into_inner().unwrap();
+    errors.sort_by_key(|(idx, _)| *idx);
+    errors
+        .into_iter()
+        .map(|(idx, error)| (actions[idx].clone(), error))
+        .collect()
+}
+
+/// Run actions on a pool of worker threads, respecting the order they depend on.
+/// A failed action doesn't stop the others. Returns the failed actions with their errors.
+pub fn run_actions(
+    actions: Vec<SyncAction>,
+    trash: Option<&Trash>,
+    threads: usize,
+    pool: &Pool,
+) -> Vec<(SyncAction, String)> {
+    let mut phases = vec![Vec::new(); 3];
+    for action in actions.into_iter() {
+        phases[phase(&action)].push(action);
+    }
+    let mut failures = Vec::new();
+    for actions in phases.into_iter().filter(|actions| !actions.is_empty()) {
+        failures.append(&mut run_phase(actions, trash, threads, pool));
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn copy_dir(path: &str) -> SyncAction {
+        SyncAction::CopyDir {
+            src: PathBuf::from("/a").join(path),
+            dest: PathBuf::from("/b").join(path),
+        }
+    }
+
+    fn copy_file(path: &str) -> SyncAction {
+        SyncAction::CopyFile {
+            src: PathBuf::from("/a").join(path),
+            dest: PathBuf::from("/b").join(path),
+        }
+    }
+
+    fn copy_meta(path: &str) -> SyncAction {
+        SyncAction::CopyMeta {
+            src: PathBuf::from("/a").join(path),
+            dest: PathBuf::from("/b").join(path),
+        }
+    }
+
+    fn delete_file(path: &str) -> SyncAction {
+        SyncAction::DeleteFile {
+            dest: PathBuf::from("/b").join(path),
+        }
+    }
+
+    fn delete_dir(path: &str) -> SyncAction {
+        SyncAction::DeleteDir {
+            dest: PathBuf::from("/b").join(path),
+        }
+    }
+
+    #[test]
+    fn parents_are_created_first() {
+        let actions = vec![
+            copy_file("d/sub/f"),
+            copy_dir("d/sub"),
+            copy_file("d/g"),
+            copy_dir("d"),
+            copy_file("other"),
+        ];
+        let deps = dependencies(&actions);
+        assert_eq!(deps[0], vec![1, 3]);
+        assert_eq!(deps[1], vec![3]);
+        assert_eq!(deps[2], vec![3]);
+        assert!(deps[3].is_empty());
+        assert!(deps[4].is_empty());
+    }
+
+    #[test]
+    fn dirs_are_deleted_after_contents() {
+        let actions = vec![
+            delete_dir("d"),
+            delete_file("d/f"),
+            delete_dir("d/sub"),
+            delete_file("d/sub/g"),
+            delete_file("other"),
+        ];
+        let deps = dependencies(&actions);
+        assert_eq!(deps[0], vec![1, 2, 3]);
+        assert!(deps[1].is_empty());
+        assert_eq!(deps[2], vec![3]);
+        assert!(deps[4].is_empty());
+    }
+
+    #[test]
+    fn metadata_is_copied_deepest_first() {
+        let actions = vec![
+            copy_meta("d"),
+            copy_meta("d/sub/f"),
+            copy_meta("d/sub"),
+            copy_meta("e"),
+        ];
+        let deps = dependencies(&actions);
+        assert_eq!(deps[0], vec![1, 2]);
+        assert!(deps[1].is_empty());
+        assert_eq!(deps[2], vec![1]);
+        assert!(deps[3].is_empty());
+    }
+
+    #[test]
+    fn removed_and_renamed_paths_are_cleared_first() {
+        let actions = vec![
+            copy_file("p"),
+            SyncAction::Remove {
+                dest: PathBuf::from("/b/p"),
+            },
+            copy_dir("old"),
+            copy_file("new/f"),
+            SyncAction::Rename {
+                src: PathBuf::from("/b/old"),
+                dest: PathBuf::from("/b/new"),
+            },
+            copy_file("old/g"),
+        ];
+        let deps = dependencies(&actions);
+        assert_eq!(deps[0], vec![1]);
+        assert!(deps[1].is_empty());
+        assert_eq!(deps[2], vec![4]);
+        assert_eq!(deps[3], vec![4]);
+        assert!(deps[4].is_empty());
+        assert_eq!(deps[5], vec![2, 4]);
+    }
+
+    #[test]
+    fn actions_are_split_in_phases() {
+        assert_eq!(phase(&copy_file("f")), 0);
+        assert_eq!(phase(&copy_dir("d")), 0);
+        assert_eq!(phase(&delete_file("f")), 1);
+        assert_eq!(phase(&delete_dir("d")), 1);
+        assert_eq!(phase(&copy_meta("d")), 2);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::datatypes::SyncAction;
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// An include or exclude rule. A pattern ending with / only matches directories.
+/// A pattern with a / at the start or in the middle is anchored, and matched against
+/// the path from the root of the synced directory. Other patterns float, and match
+/// the name of an item at any depth.
+#[derive(Clone)]
+struct Rule {
+    include: bool,
+    dir_only: bool,
+    anchored: bool,
+    matcher: GlobMatcher,
+    // The components of an anchored pattern, None for a **
+    components: Vec<Option<GlobMatcher>>,
+}
+
+fn glob_matcher(pattern: &str) -> Result<GlobMatcher, String> {
+    GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map(|glob| glob.compile_matcher())
+        .map_err(|_| String::from("Invalid pattern"))
+}
+
+impl Rule {
+    fn new(pattern: &str, include: bool) -> Result<Rule, String> {
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        if pattern.is_empty() {
+            return Err(String::from("Empty pattern"));
+        }
+        let mut components = Vec::new();
+        if anchored {
+            for component in pattern.split('/') {
+                match component {
+                    "**" => components.push(None),
+                    _ => components.push(Some(glob_matcher(component)?)),
+                }
+            }
+        }
+        Ok(Rule {
+            include,
+            dir_only,
+            anchored,
+            matcher: glob_matcher(pattern)?,
+            components,
+        })
+    }
+
+    /// Parse a rule given as "+ pattern" to include, or "- pattern" to exclude.
+    fn parse(rule: &str) -> Result<Rule, String> {
+        match (rule.get(..2), rule.get(2..)) {
+            (Some("+ "), Some(pattern)) => Rule::new(pattern, true),
+            (Some("- "), Some(pattern)) => Rule::new(pattern, false),
+            _ => Err(String::from(
+                "Expected a rule like '+ pattern' or '- pattern'",
+            )),
+        }
+    }
+
+    fn matches(&self, relpath: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.matcher.is_match(relpath)
+        } else {
+            relpath
+                .file_name()
+                .is_some_and(|name| self.matcher.is_match(name))
+        }
+    }
+
+    // True if the pattern is anchored, and can match something inside the directory
+    fn can_match_below(&self, dir: &Path) -> bool {
+        let mut depth = 0;
+        for component in dir.iter() {
+            match self.components.get(depth) {
+                Some(Some(matcher)) if matcher.is_match(component) => depth += 1,
+                Some(None) => return true,
+                _ => return false,
+            }
+        }
+        self.components.len() > depth
+    }
+}
+
+/// Check a rule given to --filter.
+pub fn check_rule(rule: &str) -> Result<(), String> {
+    Rule::parse(rule).map(|_| ())
+}
+
+/// Decides which paths are synced. The rules are checked in order, and the first one that
+/// matches decides. Everything inside a directory excluded by a rule is skipped, unless an
+/// earlier anchored include rule can match something in it. Paths that no rule matches
+/// are skipped if they match an exclude pattern, unless they also match an include pattern.
+/// Our own files are always skipped.
+#[derive(Clone)]
+pub struct PathFilter {
+    internal: GlobSet,
+    rules: Vec<Rule>,
+    exclude: GlobSet,
+    include: GlobSet,
+}
+
+fn build_globset(patterns: &[&str]) -> Result<GlobSet, Box<dyn Error>> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns.iter() {
+        builder.add(Glob::new(pattern)?);
+    }
+    Ok(builder.build()?)
+}
+
+impl PathFilter {
+    /// The exclude and include patterns are matched against the whole path,
+    /// with * matching / too.
+    pub fn new(
+        internal: &[&str],
+        rules: &[&str],
+        exclude: &[&str],
+        include: &[&str],
+    ) -> Result<PathFilter, Box<dyn Error>> {
+        let mut parsed = Vec::new();
+        for rule in rules.iter() {
+            parsed.push(Rule::parse(rule)?);
+        }
+        Ok(PathFilter {
+            internal: build_globset(internal)?,
+            rules: parsed,
+            exclude: build_globset(exclude)?,
+            include: build_globset(include)?,
+        })
+    }
+
+    /// Check a path relative to the synced directory. The directories above it are
+    /// assumed to be synced.
+    pub fn is_excluded(&self, relpath: &Path, is_dir: bool) -> bool {
+        if self.internal.is_match(relpath) {
+            return true;
+        }
+        match self
+            .rules
+            .iter()
+            .position(|rule| rule.matches(relpath, is_dir))
+        {
+            Some(pos) if !self.rules[pos].include => {
+                !is_dir
+                    || !self.rules[..pos]
+                        .iter()
+                        .any(|rule| rule.include && rule.can_match_below(relpath))
+            }
+            Some(_) => false,
+            None => self.exclude.is_match(relpath) && !self.include.is_match(relpath),
+        }
+    }
+
+    /// Check a path relative to the synced directory, and the directories above it.
+    pub fn is_synced(&self, relpath: &Path, is_dir: bool) -> bool {
+        relpath
+            .ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .all(|dir| !self.is_excluded(dir, true))
+            && !self.is_excluded(relpath, is_dir)
+    }
+
+    /// True if `dir` is a directory with excluded items in it, or anywhere below it
+    /// if `recursive` is set.
+    pub fn contains_excluded(&self, root: &Path, dir: &Path, recursive: bool) -> bool {
+        if !fs::symlink_metadata(dir).is_ok_and(|m| m.is_dir()) {
+            return false;
+        }
+        WalkDir::new(dir)
+            .min_depth(1)
+            .max_depth(if recursive { usize::MAX } else { 1 })
+            .into_iter()
+            .flatten()
+            .any(|entry| {
+                entry
+                    .path()
+                    .strip_prefix(root)
+                    .is_ok_and(|relpath| self.is_excluded(relpath, entry.file_type().is_dir()))
+            })
+    }
+}
+
+/// Leave out the actions that would delete excluded items. A removed directory that still
+/// has excluded items in it is kept, together with the directories above it. A directory
+/// that would be replaced by another type of item is left alone, and the replacement is
+/// returned as failed, so that it is tried again until the excluded items are gone.
+pub fn keep_excluded<F>(
+    actions: Vec<SyncAction>,
+    contains_excluded: F,
+) -> (Vec<SyncAction>, Vec<(SyncAction, String)>)
+where
+    F: Fn(&Path, bool) -> bool,
+{
+    let mut kept = Vec::<PathBuf>::new();
+    let mut blocked = Vec::<PathBuf>::new();
+    let mut failures = Vec::new();
+    for action in actions.iter() {
+        match action {
+            SyncAction::DeleteDir { dest } if contains_excluded(dest, false) => {
+                println!("Keeping {}, it contains excluded items\r", dest.display());
+                kept.push(dest.to_path_buf());
+            }
+            SyncAction::Remove { dest } if contains_excluded(dest, true) => {
+                failures.push((
+                    action.clone(),
+                    String::from("the directory contains excluded items"),
+                ));
+                blocked.push(dest.to_path_buf());
+            }
+            _ => {}
+        }
+    }
+    let actions = actions
+        .into_iter()
+        .filter(|action| match action {
+            SyncAction::DeleteDir { dest } => !kept
+                .iter()
+                .chain(blocked.iter())
+                .any(|dir| dir.starts_with(dest)),
+            _ => !blocked.iter().any(|dir| action.dest().starts_with(dir)),
+        })
+        .collect();
+    (actions, failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(rules: &[&str]) -> PathFilter {
+        PathFilter::new(&[".twoway.json"], rules, &[], &[]).unwrap()
+    }
+
+    #[test]
+    fn exclude_patterns() {
+        let f = PathFilter::new(&[], &[], &["build", "docs/*.tmp"], &["docs/keep.tmp"]).unwrap();
+        assert!(f.is_excluded(Path::new("build"), true));
+        assert!(!f.is_excluded(Path::new("src/build"), true));
+        assert!(f.is_excluded(Path::new("docs/sub/a.tmp"), false));
+        assert!(!f.is_excluded(Path::new("docs/keep.tmp"), false));
+        // The rules come first
+        let f = PathFilter::new(&[], &["+ build"], &["build"], &[]).unwrap();
+        assert!(!f.is_excluded(Path::new("build"), true));
+    }
+
+    #[test]
+    fn excluded_items_are_kept() {
+        let dest = |path: &str| PathBuf::from("/b").join(path);
+        let actions = vec![
+            SyncAction::DeleteFile { dest: dest("d/f") },
+            SyncAction::DeleteDir {
+                dest: dest("d/cache"),
+            },
+            SyncAction::DeleteDir { dest: dest("d") },
+            SyncAction::DeleteDir { dest: dest("e") },
+            SyncAction::Remove { dest: dest("t") },
+            SyncAction::CopyFile {
+                src: PathBuf::from("/a/t"),
+                dest: dest("t"),
+            },
+            SyncAction::Remove { dest: dest("u") },
+        ];
+        // Only d/cache and t have excluded items
+        let (kept, failures) = keep_excluded(actions, |dir, recursive| {
+            dir == dest("d/cache") || (recursive && dir == dest("t"))
+        });
+        let kept = kept.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+        assert_eq!(
+            kept,
+            vec!["DeleteFile: /b/d/f", "DeleteDir: /b/e", "Remove: /b/u"]
+        );
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0.dest(), &dest("t"));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let f = filter(&["+ important.log", "- *.log"]);
+        assert!(f.is_excluded(Path::new("a/debug.log"), false));
+        assert!(!f.is_excluded(Path::new("a/important.log"), false));
+        assert!(!f.is_excluded(Path::new("a/notes.txt"), false));
+        assert!(f.is_excluded(Path::new(".twoway.json"), false));
+        let f = filter(&["- *.log", "+ important.log"]);
+        assert!(f.is_excluded(Path::new("a/important.log"), false));
+    }
+
+    #[test]
+    fn anchored_and_floating() {
+        let f = filter(&["- build", "- /docs/*.tmp"]);
+        assert!(f.is_excluded(Path::new("build"), true));
+        assert!(f.is_excluded(Path::new("src/build"), false));
+        assert!(f.is_excluded(Path::new("docs/a.tmp"), false));
+        assert!(!f.is_excluded(Path::new("docs/sub/a.tmp"), false));
+        assert!(!f.is_excluded(Path::new("src/docs/a.tmp"), false));
+    }
+
+    #[test]
+    fn dir_only() {
+        let f = filter(&["- cache/"]);
+        assert!(f.is_excluded(Path::new("a/cache"), true));
+        assert!(!f.is_excluded(Path::new("a/cache"), false));
+        assert!(!f.is_synced(Path::new("a/cache/file"), false));
+    }
+
+    #[test]
+    fn only_some_dirs() {
+        let f = filter(&["+ src/**", "+ /docs/**", "- *"]);
+        assert!(!f.is_excluded(Path::new("src"), true));
+        assert!(!f.is_excluded(Path::new("src/a/b.rs"), false));
+        assert!(!f.is_excluded(Path::new("docs"), true));
+        assert!(f.is_excluded(Path::new("other"), true));
+        assert!(f.is_excluded(Path::new("readme"), false));
+        assert!(f.is_synced(Path::new("docs/a/b.md"), false));
+        assert!(!f.is_synced(Path::new("other/src/a.rs"), false));
+    }
+}
@@ -1,107 +1,421 @@
+mod config;
+mod conflicts;
+mod daemon;
 mod datatypes;
+mod executor;
+mod filter;
+mod pool;
+mod renames;
+mod retry;
+mod review;
+mod settle;
+mod state;
+mod trash;
+mod walk;
+mod watcher;
 
 use chrono::{DateTime, Local, TimeZone};
-use clap::{App, Arg, ArgGroup};
-use datatypes::{ChangeType, DiffItem, DirIndex, FileType, PathData, RunAction, SyncAction};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use config::Settings;
+use conflicts::{
+    log_conflicts, merge_diffs, parse_rule, print_conflicts, root_name, Conflict, ConflictPolicies,
+    ConflictPolicy, Decision, CONFLICTLOGNAME,
+};
+use daemon::SyncStatus;
+use datatypes::{
+    compare_mtime, is_tempfile, ChangeType, DiffItem, DirIndex, FileType, PathData, SyncAction,
+    INDEX_VERSION,
+};
+use executor::run_actions;
 use filetime::FileTime;
-use globset::{Glob, GlobSet, GlobSetBuilder};
+use filter::PathFilter;
+use globset::Glob;
+use pool::Pool;
+use renames::detect_renames;
+use retry::{overlay_entries, RetryQueue, RETRYFILENAME};
+use review::review_conflicts;
+use settle::SettleTracker;
+use state::StateFiles;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
 use std::fs::File;
-use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
-use std::sync::mpsc;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::{Duration, SystemTime};
+use trash::{Trash, TrashConfig, TRASHDIRNAME};
+use walk::{entry_metadata, walk_parallel};
 use walkdir::WalkDir;
+use watcher::ChangeWatcher;
+use xxhash_rust::xxh3::Xxh3;
 
-use std::io::{stdin, stdout, Read, Write};
+use std::io::{stdin, stdout, BufReader, Read, Write};
 use termion::event::Key;
 use termion::input::TermRead;
 use termion::raw::IntoRawMode;
 
 const INDEXFILENAME: &str = ".twoway.json";
 
+// Directories are named by a letter
+const MAX_ROOTS: usize = 26;
+
 enum Command {
     SyncAndExit,
     SyncNow,
     ExitNow,
+    AbandonRetries,
+    ConfirmDeletions,
+    ShowConflicts,
+    ReviewConflicts(mpsc::Sender<Vec<Conflict>>),
+    ResolveConflicts(HashMap<PathBuf, Decision>),
+    Pause,
+    Resume,
 }
 
-fn map_dir(basepath: &PathBuf, exclude_globs: &GlobSet) -> Result<DirIndex, Box<dyn Error>> {
-    let basepath_str = basepath.to_str().unwrap();
-    let current_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)?
-        .as_secs();
+struct SyncOptions {
+    // Put before the messages of a pair, when several are synced
+    label: String,
+    interval: u64,
+    filter: PathFilter,
+    max_delete: usize,
+    max_delete_percent: f64,
+    allow_mass_delete: bool,
+    trash: Option<TrashConfig>,
+    hash: bool,
+    mtime_tolerance: i128,
+    conflict_policies: ConflictPolicies,
+    inotify: bool,
+    full_scan_interval: u64,
+    settle: u64,
+    threads: usize,
+    pool: Arc<Pool>,
+    trust_dir_mtime: bool,
+    interactive: bool,
+    assume_yes: bool,
+}
+
+// Exit codes
+const EXIT_OK: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+const EXIT_ABORTED: i32 = 2;
+const EXIT_RETRIES: i32 = 3;
+const EXIT_PAUSED: i32 = 4;
+const EXIT_CONFLICTS: i32 = 5;
+
+// Set by SIGTERM and SIGINT when running without a terminal
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP_REQUESTED.store(true, AtomicOrdering::SeqCst);
+}
+
+fn handle_stop_signals() {
+    let handler = request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+}
+
+fn hash_file(path: &std::path::Path) -> Result<u64, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Xxh3::new();
+    let mut buffer = vec![0; 65536];
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+    }
+    Ok(hasher.digest())
+}
+
+// Reuse the hash from the previous index if size and mtime are unchanged.
+// Files modified in the same second as the previous scan are always rehashed,
+// since they may have changed again after being scanned.
+fn lookup_hash(
+    path: &std::path::Path,
+    relpath: &PathBuf,
+    mtime: &FileTime,
+    size: u64,
+    previous: Option<&DirIndex>,
+) -> Option<u64> {
+    if let Some(prev_index) = previous {
+        if let Some(prev) = prev_index.contents.get(relpath) {
+            if prev.hash.is_some()
+                && prev.size == size
+                && prev.mtime == mtime.seconds()
+                && prev.mtime_nsec == mtime.nanoseconds()
+                && mtime.seconds() < prev_index.scantime as i64
+            {
+                return prev.hash;
+            }
+        }
+    }
+    hash_file(path).ok()
+}
+
+fn path_data(
+    path: &std::path::Path,
+    relpath: &PathBuf,
+    m: &fs::Metadata,
+    options: &SyncOptions,
+    previous: Option<&DirIndex>,
+) -> PathData {
+    let mtime = FileTime::from_last_modification_time(m);
+    let ftype = file_type(&m.file_type());
+    let hash = if options.hash && ftype == FileType::File {
+        lookup_hash(path, relpath, &mtime, m.len(), previous)
+    } else {
+        None
+    };
+    // The ctime of directories is needed to tell if they can be skipped, see scan_tree
+    let (ctime, ctime_nsec) = if ftype == FileType::Dir {
+        (m.ctime(), m.ctime_nsec() as u32)
+    } else {
+        (0, 0)
+    };
+    PathData {
+        mtime: mtime.seconds(),
+        mtime_nsec: mtime.nanoseconds(),
+        perms: m.permissions().mode(),
+        size: m.len(),
+        ftype,
+        hash,
+        ino: m.ino(),
+        ctime,
+        ctime_nsec,
+    }
+}
+
+fn file_type(ft: &fs::FileType) -> FileType {
+    if ft.is_dir() {
+        FileType::Dir
+    } else if ft.is_symlink() {
+        FileType::Link
+    } else {
+        FileType::File
+    }
+}
+
+// Add everything below start to paths, start itself is not included.
+// Directories that have the same mtime and ctime as in the last scan still have the
+// same entries. They are only listed, and the entries of the files in them are
+// taken from the last scan instead of being looked at again.
+fn scan_tree(
+    basepath: &PathBuf,
+    start: &Path,
+    options: &SyncOptions,
+    previous: Option<&DirIndex>,
+    last_scan: Option<&DirIndex>,
+    paths: &mut HashMap<PathBuf, PathData>,
+) -> Result<(), Box<dyn Error>> {
+    let entries = walk_parallel(
+        start,
+        false,
+        options.threads,
+        &options.pool,
+        |path, entry| {
+            !is_tempfile(path)
+                && !options.filter.is_excluded(
+                    path.strip_prefix(basepath).unwrap(),
+                    entry.file_type().is_ok_and(|ft| ft.is_dir()),
+                )
+        },
+        |path, entry, dir_unchanged| {
+            let relpath = path.strip_prefix(basepath).unwrap().to_path_buf();
+            let last = last_scan.and_then(|scan| scan.contents.get(&relpath));
+            if let (true, Some(last), Ok(ft)) = (*dir_unchanged, last, entry.file_type()) {
+                if last.ftype != FileType::Dir && last.ftype == file_type(&ft) {
+                    return Ok(((relpath, last.clone()), None));
+                }
+            }
+            let m = entry_metadata(path)?;
+            let pathdata = path_data(path, &relpath, &m, options, previous);
+            let subdir = match pathdata.ftype {
+                FileType::Dir => Some(last.is_some_and(|last| {
+                    last.ftype == FileType::Dir
+                        && last.mtime_ns() == pathdata.mtime_ns()
+                        && last.ctime_ns() == pathdata.ctime_ns()
+                })),
+                _ => None,
+            };
+            Ok(((relpath, pathdata), subdir))
+        },
+    )?;
+    paths.extend(entries);
+    Ok(())
+}
+
+fn map_dir(
+    basepath: &PathBuf,
+    options: &SyncOptions,
+    previous: Option<&DirIndex>,
+) -> Result<DirIndex, Box<dyn Error>> {
+    let scantime = current_time()?;
     let mut paths = HashMap::new();
-    let depth = usize::max_value();
-    for direntry in WalkDir::new(basepath.clone())
-        .follow_links(false)
-        .max_depth(depth)
-        .into_iter()
-        .filter_entry(|e| !exclude_globs.is_match(e.path().strip_prefix(&basepath_str).unwrap()))
-        .skip(1)
-    {
-        let entry = direntry?;
-        let path = entry.path();
-        let m = entry.metadata()?;
-        let mtime = FileTime::from_last_modification_time(&m).seconds();
-        let relpath = path.strip_prefix(&basepath_str).unwrap().to_path_buf();
-        let ftype = if m.file_type().is_dir() {
-            FileType::Dir
-        } else if m.file_type().is_symlink() {
-            FileType::Link
-        } else {
-            FileType::File
-        };
-        paths.insert(
-            relpath,
-            PathData {
-                mtime: mtime,
-                perms: m.permissions().mode(),
-                size: m.len(),
-                ftype: ftype,
-            },
-        );
+    scan_tree(basepath, basepath, options, previous, None, &mut paths)?;
+    Ok(DirIndex {
+        version: INDEX_VERSION,
+        scantime,
+        root: basepath.to_path_buf(),
+        pair: previous.and_then(|previous| previous.pair.clone()),
+        contents: paths,
+    })
+}
+
+// Like map_dir, but only list the directories that are unchanged since the last scan
+fn quick_scan(
+    basepath: &PathBuf,
+    options: &SyncOptions,
+    last_scan: &DirIndex,
+) -> Result<DirIndex, Box<dyn Error>> {
+    let scantime = current_time()?;
+    let mut paths = HashMap::new();
+    let last = Some(last_scan);
+    scan_tree(basepath, basepath, options, last, last, &mut paths)?;
+    Ok(DirIndex {
+        version: INDEX_VERSION,
+        scantime,
+        root: basepath.to_path_buf(),
+        pair: last_scan.pair.clone(),
+        contents: paths,
+    })
+}
+
+// Update a previous scan by rescanning only the changed paths, and everything below them.
+// The parent dirs are checked again too, since their mtimes change when entries are added or removed.
+fn rescan_paths(
+    basepath: &PathBuf,
+    options: &SyncOptions,
+    previous: &DirIndex,
+    changed: &[PathBuf],
+) -> Result<DirIndex, Box<dyn Error>> {
+    if changed.iter().any(|path| path.as_os_str().is_empty()) {
+        return map_dir(basepath, options, Some(previous));
+    }
+    let scantime = current_time()?;
+    let mut subtrees = changed.to_vec();
+    subtrees.sort();
+    subtrees.dedup();
+    let mut top = Vec::<PathBuf>::new();
+    for path in subtrees {
+        if !top.iter().any(|other| path.starts_with(other)) {
+            top.push(path);
+        }
+    }
+    let mut paths = previous.contents.clone();
+    for relpath in top.iter() {
+        paths.retain(|path, _| !path.starts_with(relpath));
+        let path = basepath.join(relpath);
+        if is_tempfile(&path) {
+            continue;
+        }
+        if let Ok(m) = fs::symlink_metadata(&path) {
+            if options.filter.is_excluded(relpath, m.is_dir()) {
+                continue;
+            }
+            let pathdata = path_data(&path, relpath, &m, options, Some(previous));
+            paths.insert(relpath.to_path_buf(), pathdata);
+            if m.file_type().is_dir() {
+                scan_tree(basepath, &path, options, Some(previous), None, &mut paths)?;
+            }
+        }
+    }
+    for relpath in top.iter().filter_map(|path| path.parent()) {
+        if relpath.as_os_str().is_empty() || !paths.contains_key(relpath) {
+            continue;
+        }
+        let path = basepath.join(relpath);
+        match fs::symlink_metadata(&path) {
+            Ok(m) => {
+                let pathdata =
+                    path_data(&path, &relpath.to_path_buf(), &m, options, Some(previous));
+                paths.insert(relpath.to_path_buf(), pathdata);
+            }
+            Err(_) => {
+                paths.remove(relpath);
+            }
+        }
     }
     Ok(DirIndex {
-        scantime: current_time,
+        version: INDEX_VERSION,
+        scantime,
         root: basepath.to_path_buf(),
+        pair: previous.pair.clone(),
         contents: paths,
     })
 }
 
+// Remove temporary files left behind by interrupted copies
+fn clean_tempfiles(basepath: &PathBuf) -> Result<(), Box<dyn Error>> {
+    for direntry in WalkDir::new(basepath)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|e| e.file_name() != TRASHDIRNAME)
+    {
+        let entry = direntry?;
+        if is_tempfile(entry.path()) && !entry.file_type().is_dir() {
+            println!("Removing stale temporary file {}\r", entry.path().display());
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
 fn compare_dirs(
     dir_new: &DirIndex,
     dir_ref: &DirIndex,
+    mtime_tolerance: i128,
 ) -> Result<HashMap<PathBuf, DiffItem>, Box<dyn Error>> {
     let mut diffs = HashMap::new();
 
     let mut dir_ref_copy = dir_ref.clone();
     for (path, pathdata_new) in dir_new.contents.iter() {
         match dir_ref.contents.get(path) {
+            Some(pathdata_ref) if pathdata_new.ftype != pathdata_ref.ftype => {
+                //println!("{} found, type changed", path.display());
+                diffs.insert(
+                    path.to_path_buf(),
+                    DiffItem::new(ChangeType::TypeChanged, pathdata_new),
+                );
+                dir_ref_copy.contents.remove(path);
+            }
             Some(pathdata_ref) => {
-                if pathdata_new == pathdata_ref {
+                let mtime_order = compare_mtime(
+                    pathdata_new.mtime_ns(),
+                    pathdata_ref.mtime_ns(),
+                    mtime_tolerance,
+                );
+                if mtime_order == Ordering::Equal && pathdata_new.same_attributes(pathdata_ref) {
                     //println!("{} found, identical", path.display());
-                } else if pathdata_new.mtime > pathdata_ref.mtime {
+                } else if pathdata_new.same_content(pathdata_ref)
+                    && pathdata_new.perms == pathdata_ref.perms
+                {
+                    //println!("{} found, only mtime changed", path.display());
+                } else if mtime_order == Ordering::Greater {
                     //println!("{} found, N is newer", path.display());
                     diffs.insert(
                         path.to_path_buf(),
-                        DiffItem::new(ChangeType::Newer, pathdata_new.ftype, pathdata_new.mtime),
+                        DiffItem::new(ChangeType::Newer, pathdata_new),
                     );
-                } else if pathdata_new.mtime < pathdata_ref.mtime {
+                } else if mtime_order == Ordering::Less {
                     //println!("{} found, R is newer", path.display());
                     diffs.insert(
                         path.to_path_buf(),
-                        DiffItem::new(ChangeType::Older, pathdata_new.ftype, pathdata_new.mtime),
+                        DiffItem::new(ChangeType::Older, pathdata_new),
                     );
                 } else {
                     //println!("{} found, different", path.display());
                     // mode (or size, unlikely) changed
                     diffs.insert(
                         path.to_path_buf(),
-                        DiffItem::new(ChangeType::Modified, pathdata_new.ftype, pathdata_new.mtime),
+                        DiffItem::new(ChangeType::Modified, pathdata_new),
                     );
                 }
                 dir_ref_copy.contents.remove(path);
@@ -110,7 +424,7 @@ fn compare_dirs(
                 //println!("{} is missing from R.", path.display());
                 diffs.insert(
                     path.to_path_buf(),
-                    DiffItem::new(ChangeType::NewOnly, pathdata_new.ftype, pathdata_new.mtime),
+                    DiffItem::new(ChangeType::NewOnly, pathdata_new),
                 );
             }
         }
@@ -124,7 +438,7 @@ fn compare_dirs(
                 //println!("{} is missing from N.", path.display());
                 diffs.insert(
                     path.to_path_buf(),
-                    DiffItem::new(ChangeType::RefOnly, pathdata_ref.ftype, pathdata_ref.mtime),
+                    DiffItem::new(ChangeType::RefOnly, pathdata_ref),
                 );
             }
         }
@@ -132,64 +446,69 @@ fn compare_dirs(
     Ok(diffs)
 }
 
-fn solve_conflicts(
-    diff_master: &mut HashMap<PathBuf, DiffItem>,
-    diff_copy: &mut HashMap<PathBuf, DiffItem>,
-) -> Result<(), Box<dyn Error>> {
-    for (path, diffitem_master) in diff_master.clone().iter() {
-        match diff_copy.get(path) {
-            Some(diffitem_copy) => {
-                match (&diffitem_master.diff, &diffitem_copy.diff) {
-                    (ChangeType::Newer, ChangeType::Newer)
-                    | (ChangeType::Newer, ChangeType::Older)
-                    | (ChangeType::Older, ChangeType::Newer)
-                    | (ChangeType::Older, ChangeType::Older)
-                    | (ChangeType::NewOnly, ChangeType::NewOnly) => {
-                        //check which is newer, remove oldest
-                        if diffitem_master.mtime >= diffitem_copy.mtime {
-                            diff_copy.remove(path);
-                        } else {
-                            diff_master.remove(path);
-                        }
-                    }
-                    (ChangeType::RefOnly, ChangeType::RefOnly) => {
-                        //fine, remove both
-                        diff_copy.remove(path);
-                        diff_master.remove(path);
-                    }
-                    (ChangeType::Modified, ChangeType::Modified) | (ChangeType::Newer, _) => {
-                        //keep master
-                        diff_copy.remove(path);
-                    }
-                    (_, ChangeType::Newer) => {
-                        //keep copy
-                        diff_master.remove(path);
-                    }
-                    _ => {}
-                }
+// Files that only got a new mtime, with the same contents, or within the tolerance, give
+// no diff. Their new mtimes are taken into the index, otherwise they would be hashed again
+// on every full scan. Returns true if the index was changed.
+fn take_unchanged(
+    index: &mut DirIndex,
+    index_new: &DirIndex,
+    diff: &HashMap<PathBuf, DiffItem>,
+) -> bool {
+    let mut changed = false;
+    for (path, pathdata_new) in index_new.contents.iter() {
+        if pathdata_new.ftype != FileType::File || diff.contains_key(path) {
+            continue;
+        }
+        if let Some(pathdata) = index.contents.get_mut(path) {
+            if pathdata.mtime_ns() != pathdata_new.mtime_ns() {
+                *pathdata = pathdata_new.clone();
+                changed = true;
             }
-            None => {}
         }
     }
-    Ok(())
+    changed
+}
+
+// Check if the deletions in a diff are too many to apply without confirmation
+fn is_mass_deletion(
+    diff: &HashMap<PathBuf, DiffItem>,
+    index: &DirIndex,
+    max_delete: usize,
+    max_delete_percent: f64,
+) -> Option<(usize, f64)> {
+    let deletions = diff
+        .values()
+        .filter(|diffitem| diffitem.diff == ChangeType::RefOnly)
+        .count();
+    if deletions == 0 {
+        return None;
+    }
+    let percent = 100.0 * deletions as f64 / index.contents.len().max(1) as f64;
+    if deletions > max_delete || percent > max_delete_percent {
+        Some((deletions, percent))
+    } else {
+        None
+    }
+}
+
+fn current_time() -> Result<u64, Box<dyn Error>> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs())
 }
 
 fn append_base_path(path: &PathBuf, root: &PathBuf) -> PathBuf {
     [root, path].iter().collect::<PathBuf>()
 }
 
-fn save_index(idx: &DirIndex, path: &PathBuf) -> Result<(), Box<dyn Error>> {
+fn save_index(idx: &DirIndex, jsonpath: &PathBuf) -> Result<(), Box<dyn Error>> {
     let serialized = serde_json::to_string(&idx)?;
-    let mut jsonpath = PathBuf::from(path);
-    jsonpath.push(INDEXFILENAME);
     let mut jsonfile = File::create(jsonpath)?;
     jsonfile.write_all(serialized.as_bytes())?;
     Ok(())
 }
 
-fn load_index(path: &PathBuf) -> Result<DirIndex, Box<dyn Error>> {
-    let mut jsonpath = PathBuf::from(path);
-    jsonpath.push(INDEXFILENAME);
+fn load_index(jsonpath: &PathBuf) -> Result<DirIndex, Box<dyn Error>> {
     let mut jsonfile = File::open(jsonpath)?;
     let mut contents = String::new();
     jsonfile.read_to_string(&mut contents)?;
@@ -197,29 +516,59 @@ fn load_index(path: &PathBuf) -> Result<DirIndex, Box<dyn Error>> {
     Ok(idx)
 }
 
-fn process_queue(mut action_queue: Vec<SyncAction>) -> Result<(), Box<dyn Error>> {
-    action_queue.sort();
-    for action in action_queue.drain(..) {
-        println!("{}\r", action);
-        match action.run() {
-            Ok(_) => {}
-            Err(e) => {
-                println!("Action run error {}, {}\r", e, action);
-            }
-        }
-    }
-    Ok(())
-}
-
-fn sync_diffs(
+fn diff_actions(
     diff: &HashMap<PathBuf, DiffItem>,
     path_src: &PathBuf,
     path_dest: &PathBuf,
     keep_all: bool,
-) -> Result<(), Box<dyn Error>> {
+) -> Vec<SyncAction> {
     let mut actions = Vec::<SyncAction>::new();
+    let typechanged = diff
+        .iter()
+        .filter(|(_, diffitem)| diffitem.diff == ChangeType::TypeChanged)
+        .map(|(path, _)| path)
+        .collect::<Vec<&PathBuf>>();
     for (path, diffitem) in diff.iter() {
+        let below_typechanged = typechanged
+            .iter()
+            .any(|parent| path.starts_with(parent) && path != *parent);
         match (&diffitem.diff, keep_all) {
+            // Removed along with the entry that changed type, and left alone when merging
+            (&ChangeType::RefOnly, false) | (_, true) if below_typechanged => {}
+            (&ChangeType::TypeChanged, false) => {
+                let src = append_base_path(path, path_src);
+                let dest = append_base_path(path, path_dest);
+                actions.push(SyncAction::Remove {
+                    dest: dest.to_path_buf(),
+                });
+                actions.push(match diffitem.ftype {
+                    FileType::Link => SyncAction::CopyLink {
+                        src: src.to_path_buf(),
+                        dest: dest.to_path_buf(),
+                    },
+                    FileType::Dir => SyncAction::CopyDir {
+                        src: src.to_path_buf(),
+                        dest: dest.to_path_buf(),
+                    },
+                    FileType::File => SyncAction::CopyFile {
+                        src: src.to_path_buf(),
+                        dest: dest.to_path_buf(),
+                    },
+                });
+                actions.push(SyncAction::CopyMeta {
+                    src: src.to_path_buf(),
+                    dest: dest.to_path_buf(),
+                });
+            }
+            (&ChangeType::TypeChanged, true) => {
+                // Don't guess which one to keep when merging, the path is left out of
+                // the index and held as a conflict by the next sync
+                println!(
+                    "{} has different types in the directories, skipping\r",
+                    path.display()
+                );
+            }
+
             (&ChangeType::Newer, _) | (&ChangeType::NewOnly, _) | (&ChangeType::Modified, _) => {
                 let src = append_base_path(path, path_src);
                 let dest = append_base_path(path, path_dest);
@@ -242,6 +591,12 @@ fn sync_diffs(
                     dest: dest.to_path_buf(),
                 });
             }
+            (ChangeType::Renamed(from), _) => {
+                actions.push(SyncAction::Rename {
+                    src: append_base_path(from, path_dest),
+                    dest: append_base_path(path, path_dest),
+                });
+            }
             (&ChangeType::RefOnly, false) => {
                 let dest = append_base_path(path, path_dest);
                 actions.push(match diffitem.ftype {
@@ -277,142 +632,862 @@ fn sync_diffs(
             }
         }
     }
-    process_queue(actions)?;
-    Ok(())
+    actions
+}
+
+fn sync_diffs(
+    diff: &HashMap<PathBuf, DiffItem>,
+    path_src: &PathBuf,
+    path_dest: &PathBuf,
+    keep_all: bool,
+    trash: Option<&Trash>,
+    threads: usize,
+    pool: &Pool,
+) -> Result<Vec<(SyncAction, String)>, Box<dyn Error>> {
+    let actions = diff_actions(diff, path_src, path_dest, keep_all);
+    let failures = run_actions(actions, trash, threads, pool);
+    Ok(failures)
+}
+
+// Actions that apply the changes from each root to all the others. Removals are skipped
+// in roots that don't have the item anymore, and a root with an older version only needs
+// the item from one of the others.
+fn group_actions(
+    diffs: &[HashMap<PathBuf, DiffItem>],
+    roots: &[PathBuf],
+    scans: &[DirIndex],
+) -> Vec<SyncAction> {
+    let mut actions = Vec::new();
+    for (src, diff) in diffs.iter().enumerate() {
+        let first = if src == 0 { 1 } else { 0 };
+        for dest in (0..roots.len()).filter(|dest| *dest != src) {
+            let diff_dest = diff
+                .iter()
+                .filter(|(path, diffitem)| match diffitem.diff {
+                    ChangeType::RefOnly => scans[dest].contents.contains_key(*path),
+                    ChangeType::Older => dest == first,
+                    _ => true,
+                })
+                .map(|(path, diffitem)| (path.to_path_buf(), diffitem.clone()))
+                .collect::<HashMap<PathBuf, DiffItem>>();
+            actions.append(&mut diff_actions(
+                &diff_dest,
+                &roots[src],
+                &roots[dest],
+                false,
+            ));
+        }
+    }
+    actions
+}
+
+// The index isn't in the directories anymore, so an empty mount point of a drive that
+// isn't mounted would look like everything was deleted. A directory that is empty while
+// its index isn't is taken as unavailable.
+fn missing_root<'a>(roots: &'a [PathBuf], indexes: &[DirIndex]) -> Option<&'a PathBuf> {
+    roots
+        .iter()
+        .zip(indexes.iter())
+        .find(|(root, index)| {
+            !index.contents.is_empty()
+                && fs::read_dir(root).is_ok_and(|mut entries| entries.next().is_none())
+        })
+        .map(|(root, _)| root)
+}
+
+// "a and b", or "a, b and c"
+fn list_names(names: &[String]) -> String {
+    match names.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} and {}", rest.join(", "), last),
+        Some((last, _)) => last.to_string(),
+        None => String::new(),
+    }
+}
+
+fn list_roots(roots: &[PathBuf]) -> String {
+    list_names(
+        &roots
+            .iter()
+            .map(|root| root.display().to_string())
+            .collect::<Vec<String>>(),
+    )
 }
 
 fn prepare_dirs(
-    path_a: &PathBuf,
-    path_b: &PathBuf,
+    roots: &[PathBuf],
+    state: &StateFiles,
     check_only: bool,
-    exclude_globs: &GlobSet,
-) -> Result<Option<(DirIndex, DirIndex)>, Box<dyn Error>> {
-    let mut index_a: DirIndex;
-    let mut index_b: DirIndex;
-
-    match (load_index(path_a), load_index(path_b)) {
-        (Ok(idx_a), Ok(idx_b)) => {
-            index_a = idx_a;
-            index_b = idx_b;
-            let idx_time_a: DateTime<Local> = Local.timestamp(index_a.scantime as i64, 0);
-            let idx_time_b: DateTime<Local> = Local.timestamp(index_b.scantime as i64, 0);
-            println!("Using indexes from {} and {}\r", idx_time_a, idx_time_b);
-        }
-        _ => {
-            index_a = map_dir(path_a, exclude_globs)?;
-            index_b = map_dir(path_b, exclude_globs)?;
-            let diffs = compare_dirs(&index_a, &index_b)?;
-            if check_only {
-                print_diffs(&diffs);
+    options: &SyncOptions,
+) -> Result<Option<Vec<DirIndex>>, Box<dyn Error>> {
+    if !check_only {
+        for root in roots.iter() {
+            clean_tempfiles(root)?;
+        }
+        if state.migrate(roots)? {
+            println!(
+                "{}Moved the indexes from the directories to {}\r",
+                options.label,
+                state.dir.as_ref().unwrap().display()
+            );
+        }
+        state.create(roots)?;
+    }
+
+    let mut mismatch = false;
+    if let Ok(mut indexes) = state
+        .indexes
+        .iter()
+        .map(load_index)
+        .collect::<Result<Vec<DirIndex>, _>>()
+    {
+        // Indexes saved by older versions have no identity, they are only accepted if
+        // they were saved by the same sync
+        if state::saved_together(&indexes, roots, &state::save_times(&state.indexes)) {
+            let pair = state::new_identity(roots)?;
+            for (index, file) in indexes.iter_mut().zip(state.indexes.iter()) {
+                index.pair = Some(pair.clone());
+                if !check_only {
+                    save_index(index, file)?;
+                }
+            }
+        }
+        if let Some(root) = missing_root(roots, &indexes) {
+            return Err(From::from(format!(
+                "{} is empty, check that it is mounted",
+                root.display()
+            )));
+        }
+        match state::check_identity(&indexes, &state::root_ids(roots)?) {
+            Ok(()) => {
+                // The rules may have changed since the indexes were saved, and items
+                // that are excluded now must not look like they were removed
+                for index in indexes.iter_mut() {
+                    index.contents.retain(|path, pathdata| {
+                        options
+                            .filter
+                            .is_synced(path, pathdata.ftype == FileType::Dir)
+                    });
+                }
+                let idx_times = indexes
+                    .iter()
+                    .map(|index| {
+                        let idx_time: DateTime<Local> =
+                            Local.timestamp_opt(index.scantime as i64, 0).unwrap();
+                        idx_time.to_string()
+                    })
+                    .collect::<Vec<String>>();
+                println!("Using indexes from {}\r", list_names(&idx_times));
+                if indexes.iter().any(|index| index.version < INDEX_VERSION) {
+                    println!("Upgrading indexes to the current format\r");
+                    let scans = scan_all(
+                        roots
+                            .iter()
+                            .zip(indexes.iter())
+                            .map(|(root, index)| move || map_dir(root, options, Some(index)))
+                            .collect(),
+                    );
+                    for ((index, scan), file) in
+                        indexes.iter_mut().zip(scans).zip(state.indexes.iter())
+                    {
+                        index.upgrade(&scan?);
+                        save_index(index, file)?;
+                    }
+                }
+                return Ok(Some(indexes));
+            }
+            Err(reason) => {
+                // Syncing against the wrong index could delete everything that isn't in it
+                println!(
+                    "{}Warning: the indexes can't be used, {}\r",
+                    options.label, reason
+                );
+                mismatch = true;
+            }
+        }
+    }
+
+    let mut scans = scan_all(
+        roots
+            .iter()
+            .map(|root| move || map_dir(root, options, None))
+            .collect(),
+    )
+    .into_iter()
+    .collect::<Result<Vec<DirIndex>, _>>()?;
+    if check_only {
+        for (root, scan) in roots.iter().zip(scans.iter()).skip(1) {
+            let diffs = compare_dirs(&scans[0], scan, options.mtime_tolerance)?;
+            if roots.len() > 2 {
+                println!("{} compared to {}\r", roots[0].display(), root.display());
+            }
+            print_diffs(&diffs);
+        }
+        return Ok(None);
+    }
+    let names = (0..roots.len())
+        .map(|idx| root_name(idx).to_string())
+        .collect::<Vec<String>>();
+    if mismatch {
+        println!(
+            "Merging the contents of {} again, nothing will be deleted\r",
+            list_names(&names)
+        );
+    } else {
+        println!(
+            "No index found, merging the contents of {}\r",
+            list_names(&names)
+        );
+    }
+    println!(
+        "This will sync all content of \r\n> {}\r",
+        roots
+            .iter()
+            .map(|root| root.display().to_string())
+            .collect::<Vec<String>>()
+            .join("\r\nwith\r\n> ")
+    );
+    if options.assume_yes {
+        println!("Merging, confirmed by --yes\r");
+    } else if options.interactive {
+        println!("Press y to continue, any other key to abort.\r");
+        let std_in = stdin();
+        let _std_out = stdout().into_raw_mode().unwrap();
+        let key = std_in.keys().next().unwrap();
+        match key.unwrap() {
+            Key::Char('y') => {}
+            _ => {
+                println!("Exiting\r");
                 return Ok(None);
             }
-            println!("No index found, merging the contents of A and B\r");
+        };
+    } else {
+        println!("Run with --yes to confirm the merge when not running interactively.");
+        return Ok(None);
+    }
+    let trash = options.trash.map(|_| Trash::new(roots));
+    let mut retries = RetryQueue::default();
+    let mut type_conflicts = Vec::new();
+    let now = current_time()?;
+    // Merge each directory into the first one, which then has everything.
+    // The directories merged before the last one then get what they are missing.
+    let count = roots.len();
+    for (step, idx) in (1..count).chain(1..count - 1).enumerate() {
+        if step > 0 {
+            scans[0] = map_dir(&roots[0], options, Some(&scans[0]))?;
+        }
+        if step >= count - 1 {
+            scans[idx] = map_dir(&roots[idx], options, Some(&scans[idx]))?;
+        }
+        let diffs = compare_dirs(&scans[0], &scans[idx], options.mtime_tolerance)?;
+        type_conflicts.extend(
+            diffs
+                .iter()
+                .filter(|(_, diffitem)| diffitem.diff == ChangeType::TypeChanged)
+                .map(|(path, _)| path.to_path_buf()),
+        );
+        let failures = sync_diffs(
+            &diffs,
+            &roots[0],
+            &roots[idx],
+            true,
+            trash.as_ref(),
+            options.threads,
+            &options.pool,
+        )?;
+        retries.update(diffs.keys(), failures, roots, now);
+    }
+    let mut indexes = scan_all(
+        roots
+            .iter()
+            .zip(scans.iter())
+            .map(|(root, scan)| move || map_dir(root, options, Some(scan)))
+            .collect(),
+    )
+    .into_iter()
+    .collect::<Result<Vec<DirIndex>, _>>()?;
+    // Leave failed paths out of the index, the next sync will then merge them again
+    let pair = state::new_identity(roots)?;
+    for (index, file) in indexes.iter_mut().zip(state.indexes.iter()) {
+        index.pair = Some(pair.clone());
+        retries.restore_entries(index, &DirIndex::default());
+        // And paths with different types, so that they are found as conflicts
+        index
+            .contents
+            .retain(|path, _| !type_conflicts.iter().any(|other| path.starts_with(other)));
+        save_index(index, file)?;
+    }
+    retries.save(roots, &state.retries)?;
+    if !retries.is_empty() {
+        println!("{} actions failed and will be retried\r", retries.len());
+    }
+
+    println!("Done\r");
+    Ok(Some(indexes))
+}
+
+fn start_watcher(path: &Path, options: &SyncOptions) -> Option<ChangeWatcher> {
+    if !options.inotify {
+        return None;
+    }
+    match ChangeWatcher::new(path, &options.filter) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
             println!(
-                "This will sync all content of \r\n> {}\r\nwith\r\n> {}\r",
-                path_a.display(),
-                path_b.display()
+                "Unable to watch {} for changes ({}), using full scans\r",
+                path.display(),
+                e
             );
-            println!("Press y to continue, any other key to abort.\r");
-            let std_in = stdin();
-            let _std_out = stdout().into_raw_mode().unwrap();
-            let key = std_in.keys().next().unwrap();
-            match key.unwrap() {
-                Key::Char('y') => {}
-                _ => {
-                    println!("Exiting\r");
-                    return Ok(None);
-                }
-            };
-            sync_diffs(&diffs, path_a, path_b, true)?;
-            index_a = map_dir(path_a, &exclude_globs)?;
-            index_b = map_dir(path_b, &exclude_globs)?;
-            save_index(&index_a, &path_a)?;
-            save_index(&index_b, &path_b)?;
+            None
+        }
+    }
+}
+
+type ScanResult = Result<DirIndex, Box<dyn Error>>;
+
+// Scan all roots at the same time
+fn scan_all<F>(scans: Vec<F>) -> Vec<ScanResult>
+where
+    F: FnOnce() -> ScanResult + Send,
+{
+    thread::scope(|s| {
+        let handles = scans
+            .into_iter()
+            .map(|scan| s.spawn(|| scan().map_err(|e| e.to_string())))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| match handle.join() {
+                Ok(result) => result.map_err(From::from),
+                Err(_) => Err(From::from("scan thread panicked")),
+            })
+            .collect()
+    })
+}
+
+// Scan all roots of a group, using the last scans and the watchers where there are any
+fn scan_roots(
+    roots: &[PathBuf],
+    options: &SyncOptions,
+    indexes: &[DirIndex],
+    last_scans: &[Option<&DirIndex>],
+    watchers: &mut [Option<ChangeWatcher>],
+    full: bool,
+) -> Vec<ScanResult> {
+    scan_all(
+        roots
+            .iter()
+            .zip(indexes.iter())
+            .zip(last_scans.iter().zip(watchers.iter_mut()))
+            .map(|((root, index), (last, watcher))| {
+                move || scan_dir(root, options, index, *last, watcher.as_mut(), full)
+            })
+            .collect(),
+    )
+}
 
-            println!("Done\r");
+// Scan a tree, only rescanning the changed paths if a watcher knows what changed
+fn scan_dir(
+    basepath: &PathBuf,
+    options: &SyncOptions,
+    index: &DirIndex,
+    last_scan: Option<&DirIndex>,
+    watcher: Option<&mut ChangeWatcher>,
+    full: bool,
+) -> Result<DirIndex, Box<dyn Error>> {
+    // Always collect the events, a full scan covers them too
+    let changed = watcher.and_then(|watcher| watcher.changes());
+    match (last_scan, changed) {
+        (Some(last), Some(changed)) if !full => rescan_paths(basepath, options, last, &changed),
+        (Some(last), None) if !full && options.trust_dir_mtime => {
+            quick_scan(basepath, options, last)
         }
+        _ => map_dir(basepath, options, Some(index)),
     }
-    Ok(Some((index_a, index_b)))
 }
 
 // Main loop
 fn watch(
-    path_a: &PathBuf,
-    path_b: &PathBuf,
-    mut index_a: DirIndex,
-    mut index_b: DirIndex,
-    interval: u64,
-    exclude_globs: GlobSet,
+    roots: &[PathBuf],
+    state: &StateFiles,
+    mut indexes: Vec<DirIndex>,
+    options: SyncOptions,
     rx: mpsc::Receiver<Command>,
-) -> Result<(), Box<dyn Error>> {
-    let delay = Duration::from_millis(1000 * interval);
+    sync_status: Arc<Mutex<SyncStatus>>,
+) -> Result<i32, Box<dyn Error>> {
+    let delay = Duration::from_millis(1000 * options.interval);
+
+    let indexes_present = |indexes: &[DirIndex]| {
+        roots
+            .iter()
+            .all(|root| fs::metadata(root).is_ok_and(|m| m.is_dir()))
+            && state.indexes.iter().all(|file| fs::metadata(file).is_ok())
+            && missing_root(roots, indexes).is_none()
+    };
+    // A directory that is replaced while running, for example by restoring a backup,
+    // doesn't match the index anymore
+    let known_roots = indexes[0].pair.as_ref().map(|pair| pair.roots.clone());
+    let roots_replaced = || {
+        state::root_ids(roots).is_ok_and(|current| match &known_roots {
+            Some(known) => state::check_roots(known, &current).is_err(),
+            None => true,
+        })
+    };
 
-    let mut index_a_new: DirIndex;
-    let mut index_b_new: DirIndex;
+    if let Some(config) = &options.trash {
+        for root in roots.iter() {
+            trash::purge(root, config)?;
+        }
+    }
 
-    let mut diffs_a: HashMap<PathBuf, DiffItem>;
-    let mut diffs_b: HashMap<PathBuf, DiffItem>;
+    let mut retries = RetryQueue::load(&state.retries);
+    if !retries.is_empty() {
+        println!(
+            "{}{} failed actions waiting for retry\r",
+            options.label,
+            retries.len()
+        );
+    }
+    let mut abandon = false;
+    let mut confirm_deletions = false;
+    let mut deletions_paused = false;
+    let mut unresolved: Vec<Conflict> = Vec::new();
+    let mut decisions: HashMap<PathBuf, Decision> = HashMap::new();
 
-    let index_a_file: PathBuf = [&path_a, &PathBuf::from(INDEXFILENAME)].iter().collect();
-    let index_b_file: PathBuf = [&path_b, &PathBuf::from(INDEXFILENAME)].iter().collect();
+    let mut watchers = roots
+        .iter()
+        .map(|root| start_watcher(root, &options))
+        .collect::<Vec<Option<ChangeWatcher>>>();
+    if watchers.iter().any(|watcher| watcher.is_some()) {
+        println!(
+            "{}Watching for changes, full scan every {} seconds\r",
+            options.label, options.full_scan_interval
+        );
+    }
+    let mut scans: Vec<Option<DirIndex>> = vec![None; roots.len()];
+    let mut last_full_scan = 0;
+    let mut settle = SettleTracker::new(options.settle);
 
-    let _std_out = stdout().into_raw_mode().unwrap();
+    let _std_out = if options.interactive {
+        Some(stdout().into_raw_mode().unwrap())
+    } else {
+        None
+    };
     let mut run = true;
+    let mut paused = false;
+    let mut cycle_ran = false;
+    let mut cycle_errors: Vec<String> = Vec::new();
 
     while run {
+        {
+            let mut sync_status = sync_status.lock().unwrap();
+            if cycle_ran {
+                sync_status.synced(
+                    &cycle_errors,
+                    &retries,
+                    &unresolved,
+                    &settle.held,
+                    deletions_paused,
+                );
+                cycle_ran = false;
+            }
+            sync_status.state = String::from(if paused { "paused" } else { "idle" });
+        }
         run = match rx.recv_timeout(delay) {
             Ok(Command::SyncAndExit) => false,
             Ok(Command::SyncNow) => true,
             Ok(Command::ExitNow) => break,
+            Ok(Command::AbandonRetries) => {
+                abandon = true;
+                true
+            }
+            Ok(Command::ConfirmDeletions) => {
+                confirm_deletions = true;
+                true
+            }
+            Ok(Command::ShowConflicts) => {
+                unresolved.retain(|conflict| !conflict.is_resolved(roots));
+                print_conflicts(&unresolved, &options.label);
+                continue;
+            }
+            Ok(Command::ReviewConflicts(reply)) => {
+                let held = unresolved
+                    .iter()
+                    .filter(|conflict| conflict.is_held())
+                    .cloned()
+                    .collect::<Vec<Conflict>>();
+                if held.is_empty() || reply.send(held).is_err() {
+                    continue;
+                }
+                // Nothing is synced while the review screen is open
+                match rx.recv() {
+                    Ok(Command::ResolveConflicts(new_decisions)) => {
+                        if new_decisions.is_empty() {
+                            continue;
+                        }
+                        decisions.extend(new_decisions);
+                        true
+                    }
+                    _ => break,
+                }
+            }
+            Ok(Command::ResolveConflicts(new_decisions)) => {
+                decisions.extend(new_decisions);
+                true
+            }
+            Ok(Command::Pause) => {
+                if !paused {
+                    println!("{}Syncing is paused\r", options.label);
+                    paused = true;
+                }
+                continue;
+            }
+            Ok(Command::Resume) => {
+                if paused {
+                    println!("{}Syncing is resumed\r", options.label);
+                    paused = false;
+                }
+                true
+            }
+            // While paused, only explicit sync requests are served
+            Err(mpsc::RecvTimeoutError::Timeout) if paused => continue,
             Err(mpsc::RecvTimeoutError::Timeout) => true,
             Err(mpsc::RecvTimeoutError::Disconnected) => break,
         };
-        if fs::metadata(&index_a_file).is_ok() && fs::metadata(&index_b_file).is_ok() {
-            if let (Ok(idx_a), Ok(idx_b)) = (
-                map_dir(path_a, &exclude_globs),
-                map_dir(path_b, &exclude_globs),
-            ) {
-                index_a_new = idx_a;
-                index_b_new = idx_b;
-            } else {
-                println!("One scan task encountered an error!\r");
+        cycle_ran = true;
+        cycle_errors.clear();
+        sync_status.lock().unwrap().state = String::from("syncing");
+        if roots_replaced() {
+            println!(
+                "{}A directory has been replaced, restart to merge it again!\r",
+                options.label
+            );
+            cycle_errors.push(String::from("a directory has been replaced"));
+        } else if indexes_present(&indexes) {
+            let now = current_time()?;
+            let full = now >= last_full_scan + options.full_scan_interval;
+            if full {
+                last_full_scan = now;
+            }
+            let last_scans = scans
+                .iter()
+                .map(|scan| scan.as_ref())
+                .collect::<Vec<Option<&DirIndex>>>();
+            let results = scan_roots(roots, &options, &indexes, &last_scans, &mut watchers, full);
+            if results.iter().any(|result| result.is_err()) {
+                println!("{}One scan task encountered an error!\r", options.label);
+                cycle_errors.extend(
+                    results
+                        .into_iter()
+                        .filter_map(|result| result.err())
+                        .map(|e| e.to_string()),
+                );
+                // Changes may have been missed, start over with a full scan
+                scans = vec![None; roots.len()];
                 continue;
             }
+            let indexes_new = results
+                .into_iter()
+                .filter_map(|result| result.ok())
+                .collect::<Vec<DirIndex>>();
+            scans = indexes_new.iter().cloned().map(Some).collect();
             let syncresult: Result<(), Box<dyn Error>> = {
-                diffs_a = compare_dirs(&index_a_new, &index_a).unwrap();
-                diffs_b = compare_dirs(&index_b_new, &index_b).unwrap();
-                if !diffs_a.is_empty() || !diffs_b.is_empty() {
-                    if fs::metadata(&index_a_file).is_ok() && fs::metadata(&index_b_file).is_ok() {
-                        solve_conflicts(&mut diffs_a, &mut diffs_b).unwrap();
-                        sync_diffs(&diffs_a, path_a, path_b, false)?;
-                        sync_diffs(&diffs_b, path_b, path_a, false)?;
-                        index_a = map_dir(path_a, &exclude_globs)?;
-                        index_b = map_dir(path_b, &exclude_globs)?;
-                        save_index(&index_a, &path_a)?;
-                        save_index(&index_b, &path_b)?;
+                if abandon {
+                    abandon = false;
+                    if !retries.is_empty() {
+                        // Accept the current state of the failed paths as synced
+                        println!(
+                            "{}Abandoning {} failed actions\r",
+                            options.label,
+                            retries.len()
+                        );
+                        for ((index, index_new), file) in indexes
+                            .iter_mut()
+                            .zip(indexes_new.iter())
+                            .zip(state.indexes.iter())
+                        {
+                            overlay_entries(index, index_new, retries.items.keys());
+                            save_index(index, file)?;
+                        }
+                        retries.clear();
+                        retries.save(roots, &state.retries)?;
+                    }
+                }
+                let now = current_time()?;
+                let mut diffs = indexes_new
+                    .iter()
+                    .zip(indexes.iter())
+                    .map(|(index_new, index)| {
+                        compare_dirs(index_new, index, options.mtime_tolerance).unwrap()
+                    })
+                    .collect::<Vec<HashMap<PathBuf, DiffItem>>>();
+                for (((index, index_new), diff), file) in indexes
+                    .iter_mut()
+                    .zip(indexes_new.iter())
+                    .zip(diffs.iter())
+                    .zip(state.indexes.iter())
+                {
+                    if take_unchanged(index, index_new, diff) {
+                        save_index(index, file)?;
+                    }
+                }
+                for diff in diffs.iter_mut() {
+                    retries.hold_back(diff, now);
+                }
+                for path in settle.hold_back(&mut diffs, roots, now) {
+                    println!(
+                        "{}Waiting for {} to settle\r",
+                        options.label,
+                        path.display()
+                    );
+                }
+                if diffs.iter().any(|diff| !diff.is_empty()) {
+                    if indexes_present(&indexes) && !roots_replaced() {
+                        for idx in 0..diffs.len() {
+                            let others = diffs
+                                .iter()
+                                .enumerate()
+                                .filter(|(other, _)| *other != idx)
+                                .flat_map(|(_, diff)| diff.iter())
+                                .map(|(path, diffitem)| (path.to_path_buf(), diffitem.clone()))
+                                .collect::<HashMap<PathBuf, DiffItem>>();
+                            detect_renames(
+                                &mut diffs[idx],
+                                &indexes[idx],
+                                &indexes_new[idx],
+                                &others,
+                            );
+                        }
+                        let conflicts = merge_diffs(
+                            &mut diffs,
+                            options.mtime_tolerance,
+                            &options.conflict_policies,
+                            &decisions,
+                        )
+                        .unwrap();
+                        let mass_deletions = roots
+                            .iter()
+                            .zip(diffs.iter().zip(indexes.iter()))
+                            .map(|(root, (diff, index))| {
+                                (
+                                    root,
+                                    is_mass_deletion(
+                                        diff,
+                                        index,
+                                        options.max_delete,
+                                        options.max_delete_percent,
+                                    ),
+                                )
+                            })
+                            .collect::<Vec<(&PathBuf, Option<(usize, f64)>)>>();
+                        if mass_deletions.iter().any(|(_, del)| del.is_some())
+                            && !(confirm_deletions || options.allow_mass_delete)
+                        {
+                            if !deletions_paused {
+                                for (path, del) in mass_deletions.iter() {
+                                    if let Some((count, percent)) = del {
+                                        println!(
+                                            "{}{} items ({:.1}%) were removed from {}\r",
+                                            options.label,
+                                            count,
+                                            percent,
+                                            path.display()
+                                        );
+                                    }
+                                }
+                                if options.interactive {
+                                    println!("{}Syncing is paused to avoid a mass deletion. Check that all directories are intact, then press D or run with --allow-mass-delete to apply the deletions.\r", options.label);
+                                } else {
+                                    println!("{}Syncing is paused to avoid a mass deletion. Check that all directories are intact, then run with --allow-mass-delete to apply the deletions.\r", options.label);
+                                }
+                                deletions_paused = true;
+                            }
+                            continue;
+                        }
+                        confirm_deletions = false;
+                        deletions_paused = false;
+                        // Save the losing versions before they are overwritten. If that fails,
+                        // leave the path alone so that nothing is lost.
+                        let (held, conflicts): (Vec<Conflict>, Vec<Conflict>) = conflicts
+                            .into_iter()
+                            .partition(|conflict| conflict.is_held());
+                        let new_held = held
+                            .iter()
+                            .filter(|conflict| {
+                                !unresolved.iter().any(|other| {
+                                    other.path == conflict.path && other.policy == conflict.policy
+                                })
+                            })
+                            .cloned()
+                            .collect::<Vec<Conflict>>();
+                        unresolved.retain(|conflict| !conflict.is_held());
+                        unresolved.extend(held.iter().cloned());
+                        // Decisions are used once, unless the conflict was skipped again
+                        decisions.retain(|path, _| held.iter().any(|c| &c.path == path));
+                        let mut failures = Vec::new();
+                        let mut preserved = new_held;
+                        for conflict in conflicts.into_iter() {
+                            let errors =
+                                run_actions(conflict.actions(roots), None, 1, &options.pool);
+                            if let Some((_, error)) = errors.into_iter().next() {
+                                for diff in diffs.iter_mut() {
+                                    diff.remove(&conflict.path);
+                                }
+                                failures.push((conflict.overwrite(roots), error));
+                            } else {
+                                preserved.push(conflict);
+                            }
+                        }
+                        if !preserved.is_empty() {
+                            log_conflicts(&preserved, roots)?;
+                            unresolved.extend(
+                                preserved
+                                    .into_iter()
+                                    .filter(|conflict| conflict.copy.is_some()),
+                            );
+                        }
+                        let trash = options.trash.map(|_| Trash::new(roots));
+                        let (actions, mut skipped) = filter::keep_excluded(
+                            group_actions(&diffs, roots, &indexes_new),
+                            |dir, recursive| {
+                                roots.iter().find(|root| dir.starts_with(root)).is_some_and(
+                                    |root| options.filter.contains_excluded(root, dir, recursive),
+                                )
+                            },
+                        );
+                        failures.append(&mut run_actions(
+                            actions,
+                            trash.as_ref(),
+                            options.threads,
+                            &options.pool,
+                        ));
+                        failures.append(&mut skipped);
+                        if let Some(config) = &options.trash {
+                            for root in roots.iter() {
+                                trash::purge(root, config)?;
+                            }
+                        }
+                        retries.update(
+                            diffs.iter().flat_map(|diff| diff.keys()),
+                            failures,
+                            roots,
+                            now,
+                        );
+                        let last_scans = indexes_new
+                            .iter()
+                            .map(Some)
+                            .collect::<Vec<Option<&DirIndex>>>();
+                        let mut indexes_synced = scan_roots(
+                            roots,
+                            &options,
+                            &indexes_new,
+                            &last_scans,
+                            &mut watchers,
+                            false,
+                        )
+                        .into_iter()
+                        .collect::<Result<Vec<DirIndex>, _>>()?;
+                        scans = indexes_synced.iter().cloned().map(Some).collect();
+                        let held_paths = held
+                            .iter()
+                            .map(|conflict| &conflict.path)
+                            .collect::<Vec<&PathBuf>>();
+                        for (index_synced, index) in indexes_synced.iter_mut().zip(indexes.iter()) {
+                            // Failed paths keep their old index state so they are retried
+                            retries.restore_entries(index_synced, index);
+                            // Held conflicts too, with everything inside them, so they are
+                            // found again until resolved
+                            let below_held = index_synced
+                                .contents
+                                .keys()
+                                .chain(index.contents.keys())
+                                .filter(|path| held_paths.iter().any(|held| path.starts_with(held)))
+                                .cloned()
+                                .collect::<Vec<PathBuf>>();
+                            overlay_entries(index_synced, index, below_held.iter());
+                            // And files that are still being written
+                            overlay_entries(index_synced, index, settle.held.iter());
+                        }
+                        indexes = indexes_synced;
+                        for (index, file) in indexes.iter().zip(state.indexes.iter()) {
+                            save_index(index, file)?;
+                        }
+                        retries.save(roots, &state.retries)?;
+                        unresolved.retain(|conflict| !conflict.is_resolved(roots));
+                        if !unresolved.is_empty() {
+                            print_conflicts(&unresolved, &options.label);
+                        }
+                        if options.interactive
+                            && unresolved
+                                .iter()
+                                .any(|conflict| conflict.policy == ConflictPolicy::Interactive)
+                        {
+                            println!("Press R to decide how to resolve the conflicts.\r");
+                        }
                         let local_time = Local::now();
-                        println!("Completed at {}\r", local_time);
+                        if retries.is_empty() {
+                            println!("{}Completed at {}\r", options.label, local_time);
+                        } else if !options.interactive {
+                            retries.print();
+                            println!(
+                                "{}Completed at {}, {} failed actions will be retried.\r",
+                                options.label,
+                                local_time,
+                                retries.len()
+                            );
+                        } else {
+                            retries.print();
+                            println!(
+                                "{}Completed at {}, {} failed actions will be retried. Press A to abandon them.\r",
+                                options.label,
+                                local_time,
+                                retries.len()
+                            );
+                        }
                     } else {
-                        println!("One directory became unavailable while scanning!\r");
+                        println!(
+                            "{}One directory became unavailable while scanning!\r",
+                            options.label
+                        );
+                        cycle_errors.push(String::from("a directory became unavailable"));
                     }
-                }
-                else {
+                } else if retries.is_empty() {
+                    let local_time = Local::now();
+                    println!("{}No changes, completed at {}\r", options.label, local_time);
+                } else {
                     let local_time = Local::now();
-                    println!("No changes, completed at {}\r", local_time);
+                    println!(
+                        "{}No changes, completed at {}, {} failed actions waiting for retry\r",
+                        options.label,
+                        local_time,
+                        retries.len()
+                    );
                 }
                 Ok(())
             };
             match syncresult {
                 Ok(_) => {}
                 Err(e) => {
-                    println!("Sync job returned an error {}\r", e);
+                    println!("{}Sync job returned an error {}\r", options.label, e);
+                    cycle_errors.push(e.to_string());
                 }
             };
         } else {
-            println!("One directory is unavailable!\r");
+            println!("{}One directory is unavailable!\r", options.label);
+            cycle_errors.push(String::from("a directory is unavailable"));
         }
     }
-    Ok(())
+    sync_status.lock().unwrap().state = String::from("stopped");
+    Ok(if !cycle_errors.is_empty() {
+        EXIT_ERROR
+    } else if deletions_paused {
+        EXIT_PAUSED
+    } else if !retries.is_empty() {
+        EXIT_RETRIES
+    } else if unresolved.iter().any(|conflict| conflict.is_held()) {
+        EXIT_CONFLICTS
+    } else {
+        EXIT_OK
+    })
 }
 
 fn print_diffs(diff: &HashMap<PathBuf, DiffItem>) {
@@ -422,6 +1497,27 @@ fn print_diffs(diff: &HashMap<PathBuf, DiffItem>) {
     }
 }
 
+fn restore_from_trash(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let root = PathBuf::from(matches.value_of("dir").unwrap()).canonicalize()?;
+    let path = PathBuf::from(matches.value_of("path").unwrap());
+    let relpath = match path.strip_prefix(&root) {
+        Ok(relpath) => relpath.to_path_buf(),
+        Err(_) => path,
+    };
+    trash::check_relpath(&relpath)?;
+    if matches.is_present("list") {
+        let versions = trash::list_versions(&root, &relpath);
+        if versions.is_empty() {
+            println!("{} was not found in the trash", relpath.display());
+        }
+        for version in versions.iter() {
+            println!("{}", version);
+        }
+        return Ok(());
+    }
+    trash::restore(&root, &relpath, matches.value_of("timestamp"))
+}
+
 fn is_valid_path(dir: String) -> Result<(), String> {
     match PathBuf::from(&dir).canonicalize() {
         Ok(_) => Ok(()),
@@ -439,6 +1535,44 @@ fn is_valid_uint(val: String) -> Result<(), String> {
     }
 }
 
+fn is_valid_count(val: String) -> Result<(), String> {
+    match val.parse::<u64>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(String::from("Not a number")),
+    }
+}
+
+fn is_valid_percent(val: String) -> Result<(), String> {
+    match val.parse::<f64>() {
+        Ok(pct) => match (0.0..=100.0).contains(&pct) {
+            true => Ok(()),
+            false => Err(String::from("Not a percentage between 0 and 100")),
+        },
+        Err(_) => Err(String::from("Not a number")),
+    }
+}
+
+// A tolerance of more than a day would hide real changes
+const MAX_TOLERANCE: f64 = 86400.0;
+
+fn is_valid_seconds(val: String) -> Result<(), String> {
+    match val.parse::<f64>() {
+        Ok(secs) if !secs.is_finite() => Err(String::from("Not a number")),
+        Ok(secs) if secs < 0.0 => Err(String::from("Not a positive number")),
+        Ok(secs) if secs > MAX_TOLERANCE => Err(format!("More than {} seconds", MAX_TOLERANCE)),
+        Ok(_) => Ok(()),
+        Err(_) => Err(String::from("Not a number")),
+    }
+}
+
+fn is_valid_policy(name: String) -> Result<(), String> {
+    ConflictPolicy::parse(&name).map(|_| ())
+}
+
+fn is_valid_rule(rule: String) -> Result<(), String> {
+    parse_rule(&rule).map(|_| ())
+}
+
 fn is_valid_pattern(patt: String) -> Result<(), String> {
     match Glob::new(&patt) {
         Ok(_) => Ok(()),
@@ -446,6 +1580,165 @@ fn is_valid_pattern(patt: String) -> Result<(), String> {
     }
 }
 
+fn is_valid_filter_rule(rule: String) -> Result<(), String> {
+    filter::check_rule(&rule)
+}
+
+// Options of one pair, from the command line and its profile
+fn sync_options(
+    settings: &Settings,
+    label: String,
+    single_sync: bool,
+    interactive: bool,
+    pool: &Arc<Pool>,
+) -> SyncOptions {
+    let interval = match settings.value_of("interval") {
+        Some(i) => i.parse::<u64>().unwrap(),
+        _ => 1000000,
+    };
+
+    let filter = PathFilter::new(
+        &[INDEXFILENAME, RETRYFILENAME, TRASHDIRNAME, CONFLICTLOGNAME],
+        &settings.values_of("filter").unwrap_or_default(),
+        &settings.values_of("exclude").unwrap_or_default(),
+        &settings.values_of("include").unwrap_or_default(),
+    )
+    .unwrap();
+
+    let max_delete = match settings.value_of("max_delete") {
+        Some(n) => n.parse::<usize>().unwrap(),
+        _ => 1000,
+    };
+
+    let max_delete_percent = match settings.value_of("max_delete_percent") {
+        Some(p) => p.parse::<f64>().unwrap(),
+        _ => 50.0,
+    };
+
+    let mtime_tolerance = match settings.value_of("tolerance") {
+        Some(t) => (t.parse::<f64>().unwrap() * 1e9).round() as i128,
+        _ => 0,
+    };
+
+    let trash = if settings.is_present("trash") {
+        Some(TrashConfig {
+            max_age_days: settings
+                .value_of("trash_days")
+                .map(|d| d.parse::<u64>().unwrap()),
+            max_size_mb: settings
+                .value_of("trash_size")
+                .map(|s| s.parse::<u64>().unwrap()),
+        })
+    } else {
+        None
+    };
+
+    let conflict_policy = match settings.value_of("conflict") {
+        Some(p) => ConflictPolicy::parse(p).unwrap(),
+        _ => ConflictPolicy::KeepBoth,
+    };
+    let conflict_rules = match settings.values_of("conflict_rule") {
+        Some(rules) => rules.iter().map(|r| parse_rule(r).unwrap()).collect(),
+        _ => Vec::new(),
+    };
+    let conflict_policies = ConflictPolicies::new(conflict_policy, &conflict_rules).unwrap();
+
+    let full_scan_interval = match settings.value_of("full_scan") {
+        Some(i) => i.parse::<u64>().unwrap(),
+        _ => 3600,
+    };
+
+    // A single sync sees each file only once, so it can't wait for anything to settle
+    let settle = match settings.value_of("settle") {
+        _ if single_sync => 0,
+        Some(s) => s.parse::<u64>().unwrap(),
+        _ => 2,
+    };
+
+    let threads = match settings.value_of("threads") {
+        Some(t) => t.parse::<usize>().unwrap().max(1),
+        _ => 8,
+    };
+
+    SyncOptions {
+        label,
+        interval,
+        filter,
+        max_delete,
+        max_delete_percent,
+        allow_mass_delete: settings.is_present("allow_mass_delete"),
+        trash,
+        hash: settings.is_present("hash"),
+        mtime_tolerance,
+        conflict_policies,
+        inotify: !single_sync && !settings.is_present("no_inotify"),
+        full_scan_interval,
+        settle,
+        threads,
+        pool: pool.clone(),
+        trust_dir_mtime: settings.is_present("trust_dir_mtime"),
+        interactive,
+        assume_yes: settings.is_present("yes"),
+    }
+}
+
+// A pair or group of directories, synced by its own watch loop
+struct Worker {
+    name: String,
+    roots: Vec<PathBuf>,
+    tx: mpsc::Sender<Command>,
+    status: Arc<Mutex<SyncStatus>>,
+    handle: thread::JoinHandle<i32>,
+}
+
+fn start_worker(
+    name: String,
+    roots: Vec<PathBuf>,
+    state: StateFiles,
+    indexes: Vec<DirIndex>,
+    options: SyncOptions,
+) -> Worker {
+    let (tx, rx) = mpsc::channel();
+    let status = Arc::new(Mutex::new(SyncStatus::new(&name, &roots)));
+    let (worker_roots, worker_status) = (roots.clone(), status.clone());
+    let handle = thread::spawn(move || {
+        let label = options.label.clone();
+        match watch(&worker_roots, &state, indexes, options, rx, worker_status) {
+            Ok(status) => status,
+            Err(e) => {
+                println!("{}Watch loop returned an error {}\r", label, e);
+                EXIT_ERROR
+            }
+        }
+    });
+    Worker {
+        name,
+        roots,
+        tx,
+        status,
+        handle,
+    }
+}
+
+fn send_all<F>(workers: &[&Worker], command: F)
+where
+    F: Fn() -> Command,
+{
+    for worker in workers.iter() {
+        // Fails only if the worker already stopped
+        let _res = worker.tx.send(command());
+    }
+}
+
+// The most serious status of all pairs, an error before anything else
+fn combined_status(statuses: &[i32]) -> i32 {
+    if statuses.contains(&EXIT_ERROR) {
+        EXIT_ERROR
+    } else {
+        statuses.iter().copied().max().unwrap_or(EXIT_OK)
+    }
+}
+
 fn main() {
     let matches = App::new("TwoWaySync")
         .version("0.1.3")
@@ -479,114 +1772,610 @@ fn main() {
                 .validator(is_valid_pattern)
                 .help("Exclude files and dirs matching pattern"),
         )
+        .arg(
+            Arg::with_name("include")
+                .long("include")
+                .takes_value(true)
+                .number_of_values(1)
+                .multiple(true)
+                .validator(is_valid_pattern)
+                .help("Sync files and dirs matching pattern even if they are excluded"),
+        )
+        .arg(
+            Arg::with_name("filter")
+                .long("filter")
+                .takes_value(true)
+                .number_of_values(1)
+                .multiple(true)
+                .validator(is_valid_filter_rule)
+                .help("Rule to include (+ pattern) or exclude (- pattern) files and dirs, checked in order before --include and --exclude"),
+        )
+        .arg(
+            Arg::with_name("max_delete")
+                .long("max-delete")
+                .takes_value(true)
+                .validator(is_valid_count)
+                .help("Pause syncing if more than this number of items are removed (default 1000)"),
+        )
+        .arg(
+            Arg::with_name("max_delete_percent")
+                .long("max-delete-percent")
+                .takes_value(true)
+                .validator(is_valid_percent)
+                .help("Pause syncing if more than this percentage of the items are removed (default 50)"),
+        )
+        .arg(
+            Arg::with_name("allow_mass_delete")
+                .long("allow-mass-delete")
+                .help("Apply deletions without confirmation even if they exceed the limits"),
+        )
+        .arg(
+            Arg::with_name("trash")
+                .long("trash")
+                .help("Move deleted and overwritten files to a trash directory"),
+        )
+        .arg(
+            Arg::with_name("trash_days")
+                .long("trash-days")
+                .takes_value(true)
+                .validator(is_valid_uint)
+                .requires("trash")
+                .help("Remove items that have been in the trash longer than this number of days"),
+        )
+        .arg(
+            Arg::with_name("trash_size")
+                .long("trash-size")
+                .takes_value(true)
+                .validator(is_valid_uint)
+                .requires("trash")
+                .help("Maximum size of the trash in MB, the oldest items are removed first"),
+        )
+        .arg(
+            Arg::with_name("hash")
+                .long("hash")
+                .help("Compare file contents, to ignore changes of only the modification time"),
+        )
+        .arg(
+            Arg::with_name("tolerance")
+                .long("mtime-tolerance")
+                .takes_value(true)
+                .validator(is_valid_seconds)
+                .help("Consider modification times equal if they differ by at most this many seconds (default 0)"),
+        )
+        .arg(
+            Arg::with_name("conflict")
+                .long("conflict")
+                .takes_value(true)
+                .validator(is_valid_policy)
+                .help("How to handle files changed in both directories (default keep-both)"),
+        )
+        .arg(
+            Arg::with_name("conflict_rule")
+                .long("conflict-rule")
+                .takes_value(true)
+                .number_of_values(1)
+                .multiple(true)
+                .validator(is_valid_rule)
+                .help("Conflict policy for paths matching a pattern, given as pattern=policy"),
+        )
+        .arg(
+            Arg::with_name("settle")
+                .long("settle")
+                .takes_value(true)
+                .validator(is_valid_count)
+                .help("Wait until changed files have been left alone for this many seconds before copying them (default 2 when watching, 0 otherwise)"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .validator(is_valid_uint)
+                .help("Number of threads used to scan each directory, and to copy files (default 8)"),
+        )
+        .arg(
+            Arg::with_name("trust_dir_mtime")
+                .long("trust-dir-mtime")
+                .help("Only list directories with unchanged mtime and ctime between full scans, instead of checking every file in them"),
+        )
+        .arg(
+            Arg::with_name("full_scan")
+                .long("full-scan")
+                .takes_value(true)
+                .validator(is_valid_uint)
+                .help("Interval in seconds between full scans when watching with inotify or --trust-dir-mtime (default 3600)"),
+        )
+        .arg(
+            Arg::with_name("no_inotify")
+                .long("no-inotify")
+                .help("Don't use inotify, do a full scan every interval"),
+        )
+        .arg(
+            Arg::with_name("non_interactive")
+                .long("non-interactive")
+                .help("Don't read keys from the terminal, default when stdin is not a terminal"),
+        )
+        .arg(
+            Arg::with_name("yes")
+                .short("y")
+                .long("yes")
+                .help("Merge the directories without asking when there is no index"),
+        )
+        .arg(
+            Arg::with_name("index_in_tree")
+                .long("index-in-tree")
+                .help("Keep the index in the synced directories instead of the state directory"),
+        )
+        .arg(
+            Arg::with_name("daemon")
+                .long("daemon")
+                .help("Run without a terminal, and take commands from the ctl subcommand"),
+        )
+        .arg(
+            Arg::with_name("socket")
+                .long("socket")
+                .takes_value(true)
+                .requires("daemon")
+                .help("Path of the control socket (default $XDG_RUNTIME_DIR/twowaysync.sock)"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .short("p")
+                .long("profile")
+                .takes_value(true)
+                .number_of_values(1)
+                .multiple(true)
+                .help("Use the directories and options of a profile in the config file, can be given more than once to sync several pairs"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .requires("profile")
+                .help("Path of the config file (default ~/.config/twowaysync/config.toml)"),
+        )
         .group(ArgGroup::with_name("sync").args(&["check", "single", "interval"]))
         .arg(
-            Arg::with_name("dir_a")
-                .help("First directory")
-                .required(true)
+            Arg::with_name("dirs")
+                .help("Directories to sync, two or more")
+                .required_unless("profile")
+                .multiple(true)
                 .validator(is_valid_path)
                 .index(1),
         )
-        .arg(
-            Arg::with_name("dir_b")
-                .help("Second directory")
-                .required(true)
-                .validator(is_valid_path)
-                .index(2),
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restore a deleted or overwritten path from the trash")
+                .arg(
+                    Arg::with_name("list")
+                        .short("l")
+                        .long("list")
+                        .help("List the versions in the trash"),
+                )
+                .arg(
+                    Arg::with_name("timestamp")
+                        .short("t")
+                        .long("timestamp")
+                        .takes_value(true)
+                        .help("Version to restore (default newest)"),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .help("Synced directory")
+                        .required(true)
+                        .validator(is_valid_path)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .help("Path to restore, relative to the directory")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ctl")
+                .about("Send a command to a running daemon")
+                .arg(
+                    Arg::with_name("socket")
+                        .long("socket")
+                        .takes_value(true)
+                        .help("Path of the control socket (default $XDG_RUNTIME_DIR/twowaysync.sock)"),
+                )
+                .arg(
+                    Arg::with_name("command")
+                        .help("Command to send")
+                        .required(true)
+                        .possible_values(&daemon::CTL_COMMANDS)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("pair")
+                        .help("Profile name of the pair to send the command to (default all)")
+                        .index(2),
+                ),
         )
         .get_matches();
 
-    let mut check_only = matches.is_present("check");
+    if let Some(restore_matches) = matches.subcommand_matches("restore") {
+        if let Err(e) = restore_from_trash(restore_matches) {
+            println!("Restore failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let single_sync = matches.is_present("single");
+    if let Some(ctl_matches) = matches.subcommand_matches("ctl") {
+        let socket = match ctl_matches.value_of("socket") {
+            Some(path) => PathBuf::from(path),
+            _ => daemon::default_socket_path(),
+        };
+        match daemon::send_command(
+            &socket,
+            ctl_matches.value_of("command").unwrap(),
+            ctl_matches.value_of("pair"),
+        ) {
+            Ok(reply) => print!("{}", reply),
+            Err(e) => {
+                println!("Command failed: {}", e);
+                std::process::exit(EXIT_ERROR);
+            }
+        }
+        return;
+    }
 
-    let path_a = match matches.value_of("dir_a") {
-        Some(path) => PathBuf::from(&path).canonicalize().unwrap(),
-        _ => PathBuf::new(),
+    let profiles = match matches.values_of("profile") {
+        Some(names) => names.collect::<Vec<&str>>(),
+        None => Vec::new(),
     };
-
-    let path_b = match matches.value_of("dir_b") {
-        Some(path) => PathBuf::from(&path).canonicalize().unwrap(),
-        _ => PathBuf::new(),
+    if profiles.len() > 1 && matches.is_present("dirs") {
+        println!("Directories can't be given on the command line together with several profiles");
+        std::process::exit(EXIT_ERROR);
+    }
+    let config_path = match matches.value_of("config") {
+        Some(path) => PathBuf::from(path),
+        _ => config::default_config_path(),
     };
-
-    let interval = match matches.value_of("interval") {
-        Some(i) => i.parse::<u64>().unwrap(),
-        _ => {
-            if !single_sync {
-                check_only = true;
+    let mut pair_settings = Vec::new();
+    for name in profiles.iter() {
+        match config::load_profile(&config_path, name) {
+            Ok(profile) => {
+                pair_settings.push((name.to_string(), Settings::new(&matches, Some(profile))))
+            }
+            Err(e) => {
+                println!("Invalid config: {}", e);
+                std::process::exit(EXIT_ERROR);
             }
-            1000000
         }
-    };
+    }
+    if pair_settings.is_empty() {
+        pair_settings.push((String::from("default"), Settings::new(&matches, None)));
+    }
 
-    let mut builder = GlobSetBuilder::new();
-    if let Some(excludes) = matches.values_of("exclude") {
-        for excl in excludes {
-            builder.add(Glob::new(&excl).unwrap());
+    let single_sync = matches.is_present("single");
+    // A profile that watches can still be used for a check or a single sync
+    let mut check_only = matches.is_present("check");
+    if !check_only && !single_sync {
+        let unwatched = pair_settings
+            .iter()
+            .filter(|(_, settings)| settings.value_of("interval").is_none())
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<&str>>();
+        if unwatched.len() == pair_settings.len() {
+            check_only = true;
+        } else if !unwatched.is_empty() {
+            println!("No watch interval for: {}", unwatched.join(", "));
+            std::process::exit(EXIT_ERROR);
         }
     }
-    builder.add(Glob::new(INDEXFILENAME).unwrap());
-    let exclude_globs = builder.build().unwrap();
+
+    let daemon_mode = matches.is_present("daemon");
+    if daemon_mode && check_only {
+        println!("The daemon needs a watch interval, given with -w or in the profile");
+        std::process::exit(EXIT_ERROR);
+    }
+    let interactive = !matches.is_present("non_interactive")
+        && !daemon_mode
+        && termion::is_tty(&stdin())
+        && termion::is_tty(&stdout());
+    if !interactive {
+        handle_stop_signals();
+    }
+
+    // All pairs share one pool, as large as the largest --threads
+    let pool_size = pair_settings
+        .iter()
+        .map(|(_, settings)| match settings.value_of("threads") {
+            Some(t) => t.parse::<usize>().unwrap(),
+            _ => 8,
+        })
+        .max()
+        .unwrap_or(8);
+    let pool = Arc::new(Pool::new(pool_size));
+    let several = pair_settings.len() > 1;
+    let mut pairs = Vec::new();
+    for (name, settings) in pair_settings.iter() {
+        let dirs = match settings.values_of("dirs") {
+            Some(dirs) => dirs,
+            None => [settings.value_of("dir_a"), settings.value_of("dir_b")]
+                .iter()
+                .flatten()
+                .copied()
+                .collect(),
+        };
+        if dirs.len() < 2 || dirs.len() > MAX_ROOTS {
+            println!(
+                "Between 2 and {} directories must be given, on the command line or in the profile",
+                MAX_ROOTS
+            );
+            std::process::exit(EXIT_ERROR);
+        }
+        let mut roots: Vec<PathBuf> = Vec::new();
+        for dir in dirs.iter() {
+            let root = PathBuf::from(dir).canonicalize().unwrap();
+            if roots.contains(&root) {
+                println!("{} is given more than once", root.display());
+                std::process::exit(EXIT_ERROR);
+            }
+            roots.push(root);
+        }
+        let label = if several {
+            format!("{}: ", name)
+        } else {
+            String::new()
+        };
+        let options = sync_options(settings, label, single_sync, interactive, &pool);
+        let state = if settings.is_present("index_in_tree") {
+            StateFiles::in_tree(&roots)
+        } else {
+            StateFiles::in_state_dir(&state::default_state_dir(), &roots)
+        };
+        pairs.push((name.to_string(), roots, state, options));
+    }
+
+    // Take the socket before anything is synced, so that a second daemon stops right away
+    let socket_path = match matches.value_of("socket") {
+        Some(path) => PathBuf::from(path),
+        _ => daemon::default_socket_path(),
+    };
+    let listener = if daemon_mode {
+        match daemon::bind_socket(&socket_path) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                println!("Unable to create the control socket: {}", e);
+                std::process::exit(EXIT_ERROR);
+            }
+        }
+    } else {
+        None
+    };
 
     let std_in = stdin();
-    let mut std_out = stdout().into_raw_mode().unwrap();
-
-    let indexes = prepare_dirs(&path_a, &path_b, check_only, &exclude_globs).unwrap();
-
-    if !check_only && indexes.is_some() {
-        let (index_a, index_b) = indexes.unwrap();
-
-        let (tx, rx) = mpsc::channel();
-        let worker = thread::spawn(move || {
-            match watch(
-                &path_a,
-                &path_b,
-                index_a,
-                index_b,
-                interval,
-                exclude_globs,
-                rx,
-            ) {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("Watch loop returned an error {}", e);
+    let mut std_out = if interactive {
+        Some(stdout().into_raw_mode().unwrap())
+    } else {
+        None
+    };
+
+    let mut statuses = Vec::new();
+    let mut workers = Vec::new();
+    for (name, roots, state, options) in pairs.into_iter() {
+        let indexes = match prepare_dirs(&roots, &state, check_only, &options) {
+            Ok(Some(indexes)) => indexes,
+            Ok(None) => {
+                if !check_only {
+                    statuses.push(EXIT_ABORTED);
                 }
+                continue;
+            }
+            Err(e) => {
+                println!(
+                    "{}Unable to prepare the directories: {}\r",
+                    options.label, e
+                );
+                statuses.push(EXIT_ERROR);
+                continue;
             }
-        });
+        };
+        if !check_only {
+            workers.push(start_worker(name, roots, state, indexes, options));
+        }
+    }
 
-        if !single_sync {
-            println!("Watching for changes every {} seconds.\r\nPress S to sync now, Q to sync now and exit, or Ctrl-C to exit immediately.\r", interval);
-            tx.send(Command::SyncNow).unwrap();
+    if workers.is_empty() {
+        // Only checked, or nothing could be synced
+    } else if let Some(listener) = listener {
+        println!(
+            "Watching for changes, listening for commands on {}",
+            socket_path.display()
+        );
+        for worker in workers.iter() {
+            worker.tx.send(Command::SyncNow).unwrap();
+        }
+        if let Err(e) = daemon::serve(listener, &socket_path, &workers, &STOP_REQUESTED) {
+            println!("Control socket failed: {}, exiting after next sync", e);
+            for worker in workers.iter() {
+                let _res = worker.tx.send(Command::SyncAndExit);
+            }
+        }
+    } else if single_sync {
+        println!("Syncing once...\r");
+        for worker in workers.iter() {
+            worker.tx.send(Command::SyncAndExit).unwrap();
+        }
+    } else if let Some(std_out) = std_out.as_mut() {
+        println!("Watching for changes.\r\nPress S to sync now, Q to sync now and exit, A to abandon failed actions, D to confirm held back deletions, C to list unresolved conflicts, R to resolve conflicts, or Ctrl-C to exit immediately.\r");
+        if workers.len() > 1 {
+            for (idx, worker) in workers.iter().enumerate() {
+                println!(
+                    "{}: {}, {}\r",
+                    idx + 1,
+                    worker.name,
+                    list_roots(&worker.roots)
+                );
+            }
+            println!(
+                "Press the number of a pair to send the commands only to it, or 0 for all pairs.\r"
+            );
+        }
+        for worker in workers.iter() {
+            worker.tx.send(Command::SyncNow).unwrap();
+        }
 
-            for c in std_in.keys() {
-                match c.unwrap() {
-                    Key::Char('q') | Key::Esc => {
-                        println!("Exiting after next sync...\r");
-                        tx.send(Command::SyncAndExit).unwrap();
-                        let _res = worker.join();
-                        break;
+        // The pair the keys are for, all of them if None
+        let mut target: Option<usize> = None;
+        // Q lets the loops finish a sync before exiting, Ctrl-C doesn't
+        let mut finish_sync = false;
+        let mut keys = std_in.keys();
+        while let Some(c) = keys.next() {
+            let targets = workers
+                .iter()
+                .enumerate()
+                .filter(|(idx, _)| target.is_none_or(|t| t == *idx))
+                .map(|(_, worker)| worker)
+                .collect::<Vec<&Worker>>();
+            match c.unwrap() {
+                Key::Char('q') | Key::Esc => {
+                    println!("Exiting after next sync...\r");
+                    finish_sync = true;
+                    break;
+                }
+                Key::Char(n) if workers.len() > 1 && n.is_ascii_digit() => {
+                    let n = n.to_digit(10).unwrap() as usize;
+                    if n == 0 {
+                        target = None;
+                        println!("Sending commands to all pairs\r");
+                    } else if n <= workers.len() {
+                        target = Some(n - 1);
+                        println!("Sending commands to {}\r", workers[n - 1].name);
                     }
-                    Key::Char('s') => {
-                        println!("Syncing now...\r");
-                        tx.send(Command::SyncNow)
+                }
+                Key::Char('s') => {
+                    println!("Syncing now...\r");
+                    send_all(&targets, || Command::SyncNow);
+                }
+                Key::Char('d') => {
+                    println!("Applying deletions...\r");
+                    send_all(&targets, || Command::ConfirmDeletions);
+                }
+                Key::Char('a') => {
+                    println!("Abandoning failed actions...\r");
+                    send_all(&targets, || Command::AbandonRetries);
+                }
+                Key::Char('c') => send_all(&targets, || Command::ShowConflicts),
+                Key::Char('r') => {
+                    let mut reviewed = false;
+                    for worker in targets.iter() {
+                        let (reply_tx, reply_rx) = mpsc::channel();
+                        if worker.tx.send(Command::ReviewConflicts(reply_tx)).is_err() {
+                            continue;
+                        }
+                        if let Ok(conflicts) = reply_rx.recv() {
+                            reviewed = true;
+                            let decisions =
+                                review_conflicts(&conflicts, &worker.roots, &mut keys, std_out)
+                                    .unwrap_or_else(|e| {
+                                        println!("Conflict review failed: {}\r", e);
+                                        HashMap::new()
+                                    });
+                            if !decisions.is_empty() {
+                                println!("Resolving {} conflicts...\r", decisions.len());
+                            }
+                            let _res = worker.tx.send(Command::ResolveConflicts(decisions));
+                        }
                     }
-                    Key::Ctrl('c') => {
-                        println!("Exiting now...\r");
-                        tx.send(Command::ExitNow).unwrap();
-                        let _res = worker.join();
-                        break;
+                    if !reviewed {
+                        println!("No conflicts waiting for a decision\r");
                     }
-                    _ => Ok(()),
                 }
-                .unwrap();
+                Key::Ctrl('c') => {
+                    println!("Exiting now...\r");
+                    break;
+                }
+                _ => {}
             }
-        } else {
-            println!("Syncing once...\r");
-            tx.send(Command::SyncAndExit).unwrap();
-            let _res = worker.join();
         }
+        for worker in workers.iter() {
+            let _res = match finish_sync {
+                true => worker.tx.send(Command::SyncAndExit),
+                false => worker.tx.send(Command::ExitNow),
+            };
+        }
+    } else {
+        println!("Watching for changes. Send SIGTERM or SIGINT to exit after the next sync.");
+        for worker in workers.iter() {
+            worker.tx.send(Command::SyncNow).unwrap();
+        }
+        let mut stopping = false;
+        while !workers.iter().all(|worker| worker.handle.is_finished()) {
+            if !stopping && STOP_REQUESTED.load(AtomicOrdering::SeqCst) {
+                println!("Exiting after next sync...");
+                for worker in workers.iter() {
+                    // Fails only if the worker already stopped
+                    let _res = worker.tx.send(Command::SyncAndExit);
+                }
+                stopping = true;
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+    for worker in workers.into_iter() {
+        statuses.push(worker.handle.join().unwrap_or(EXIT_ERROR));
+    }
+    if let Some(mut std_out) = std_out {
+        write!(std_out, "\r").unwrap();
+        write!(std_out, "{}", termion::cursor::Show).unwrap();
+    }
+    std::process::exit(combined_status(&statuses));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn removed(count: usize, total: usize) -> (HashMap<PathBuf, DiffItem>, DirIndex) {
+        let mut diff = HashMap::new();
+        let mut index = DirIndex::default();
+        for idx in 0..total {
+            let path = PathBuf::from(format!("file{}", idx));
+            let pathdata = PathData {
+                mtime: 100,
+                mtime_nsec: 0,
+                perms: 0o644,
+                size: 10,
+                ftype: FileType::File,
+                hash: None,
+                ino: 0,
+                ctime: 0,
+                ctime_nsec: 0,
+            };
+            if idx < count {
+                diff.insert(path.clone(), DiffItem::new(ChangeType::RefOnly, &pathdata));
+            }
+            index.contents.insert(path, pathdata);
+        }
+        (diff, index)
+    }
+
+    #[test]
+    fn mass_deletion_limits() {
+        // At the count limit, and one above it
+        let (diff, index) = removed(10, 1000);
+        assert_eq!(is_mass_deletion(&diff, &index, 10, 50.0), None);
+        let (diff, index) = removed(11, 1000);
+        assert_eq!(is_mass_deletion(&diff, &index, 10, 50.0), Some((11, 1.1)));
+        // At the percent limit, and above it
+        let (diff, index) = removed(5, 10);
+        assert_eq!(is_mass_deletion(&diff, &index, 1000, 50.0), None);
+        let (diff, index) = removed(6, 10);
+        assert_eq!(is_mass_deletion(&diff, &index, 1000, 50.0), Some((6, 60.0)));
+        // With --max-delete 0 any deletion counts, but nothing else does
+        let (diff, index) = removed(1, 1000);
+        assert_eq!(is_mass_deletion(&diff, &index, 0, 50.0), Some((1, 0.1)));
+        let (diff, index) = removed(0, 1000);
+        assert_eq!(is_mass_deletion(&diff, &index, 0, 50.0), None);
+        // An empty index, nothing can have been removed from it
+        let (diff, index) = removed(0, 0);
+        assert_eq!(is_mass_deletion(&diff, &index, 0, 0.0), None);
     }
-    write!(std_out, "\r").unwrap();
-    write!(std_out, "{}", termion::cursor::Show).unwrap();
 }
@@ -0,0 +1,39 @@
+use std::sync::{Condvar, Mutex};
+
+/// Limits how many directories are read and actions are run at the same time,
+/// by all scans and syncs that share it.
+pub struct Pool {
+    free: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A slot of the pool, that is given back when dropped.
+pub struct Permit<'a> {
+    pool: &'a Pool,
+}
+
+impl Pool {
+    pub fn new(size: usize) -> Pool {
+        Pool {
+            free: Mutex::new(size.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait for a free slot.
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.released.wait(free).unwrap();
+        }
+        *free -= 1;
+        Permit { pool: self }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.pool.free.lock().unwrap() += 1;
+        self.pool.released.notify_one();
+    }
+}
@@ -0,0 +1,343 @@
+use crate::datatypes::{ChangeType, DiffItem, DirIndex, FileType, PathData};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+// Check if a removed and an added entry are the same item under a new name.
+// Inodes are reused, so for files the size and mtime must match as well.
+fn is_same_item(old: &PathData, new: &PathData) -> bool {
+    if old.ftype != new.ftype {
+        return false;
+    }
+    match old.ftype {
+        FileType::File => old.same_file(new),
+        _ => old.ino != 0 && old.ino == new.ino,
+    }
+}
+
+// The added entries, looked up by inode, and files also by size and mtime
+struct Added<'a> {
+    by_ino: HashMap<u64, Vec<&'a PathBuf>>,
+    by_file: HashMap<(u64, i128), Vec<&'a PathBuf>>,
+}
+
+impl<'a> Added<'a> {
+    fn new(paths: &'a [PathBuf], index_new: &DirIndex) -> Added<'a> {
+        let mut added = Added {
+            by_ino: HashMap::new(),
+            by_file: HashMap::new(),
+        };
+        for path in paths.iter() {
+            let new = &index_new.contents[path];
+            if new.ino != 0 {
+                added.by_ino.entry(new.ino).or_default().push(path);
+            }
+            if new.ftype == FileType::File {
+                added
+                    .by_file
+                    .entry((new.size, new.mtime_ns()))
+                    .or_default()
+                    .push(path);
+            }
+        }
+        added
+    }
+}
+
+fn find_new_name(
+    old: &PathData,
+    added: &Added,
+    index_new: &DirIndex,
+    used: &HashSet<PathBuf>,
+) -> Option<PathBuf> {
+    let is_candidate =
+        |path: &&PathBuf| !used.contains(*path) && is_same_item(old, &index_new.contents[*path]);
+    if old.ino != 0 {
+        if let Some(path) = added
+            .by_ino
+            .get(&old.ino)
+            .and_then(|paths| paths.iter().copied().find(is_candidate))
+        {
+            return Some(path.to_path_buf());
+        }
+    }
+    // Without a matching inode, only accept files that can't be confused with another one
+    if old.ftype == FileType::File {
+        let candidates: Vec<&PathBuf> = added
+            .by_file
+            .get(&(old.size, old.mtime_ns()))
+            .map(|paths| paths.iter().copied().filter(is_candidate).collect())
+            .unwrap_or_default();
+        if candidates.len() == 1 {
+            return Some(candidates[0].to_path_buf());
+        }
+    }
+    None
+}
+
+// All changed paths of the other side, and the directories above them
+fn touched(diffs: &HashMap<PathBuf, DiffItem>) -> HashSet<&Path> {
+    diffs.keys().flat_map(|path| path.ancestors()).collect()
+}
+
+// Items inside a directory that was replaced by another type are removed along with it
+fn inside_typechanged(path: &Path, diffs: &HashMap<PathBuf, DiffItem>) -> bool {
+    path.ancestors()
+        .skip(1)
+        .any(|parent| diffs.get(parent).map(|d| &d.diff) == Some(&ChangeType::TypeChanged))
+}
+
+/// Find removed and added entries in a diff that are really renames, and replace them
+/// with a single Renamed item. The contents of renamed directories are checked so that
+/// only the changes made inside the directory remain in the diff, relative to the new name.
+/// Renames are skipped if the other side has any changes to the old or new path.
+pub fn detect_renames(
+    diffs: &mut HashMap<PathBuf, DiffItem>,
+    index_ref: &DirIndex,
+    index_new: &DirIndex,
+    diffs_other: &HashMap<PathBuf, DiffItem>,
+) {
+    let mut removed = diffs
+        .iter()
+        .filter(|(_, diffitem)| diffitem.diff == ChangeType::RefOnly)
+        .map(|(path, _)| path.to_path_buf())
+        .collect::<Vec<PathBuf>>();
+    removed.sort_by_key(|path| path.iter().count());
+    let added_paths = diffs
+        .iter()
+        .filter(|(_, diffitem)| diffitem.diff == ChangeType::NewOnly)
+        .map(|(path, _)| path.to_path_buf())
+        .collect::<Vec<PathBuf>>();
+    let added = Added::new(&added_paths, index_new);
+    let touched_other = touched(diffs_other);
+
+    let mut used = HashSet::new();
+    for old_path in removed.iter() {
+        // Already handled as part of a renamed parent dir
+        if diffs.get(old_path).map(|d| &d.diff) != Some(&ChangeType::RefOnly) {
+            continue;
+        }
+        let old = &index_ref.contents[old_path];
+        let new_path = match find_new_name(old, &added, index_new, &used) {
+            Some(path) => path,
+            None => continue,
+        };
+        if touched_other.contains(old_path.as_path())
+            || touched_other.contains(new_path.as_path())
+            || inside_typechanged(old_path, diffs)
+        {
+            continue;
+        }
+        used.insert(new_path.clone());
+        if old.ftype == FileType::Dir {
+            let contents = diffs
+                .keys()
+                .filter(|path| path.starts_with(old_path) && *path != old_path)
+                .cloned()
+                .collect::<Vec<PathBuf>>();
+            for path in contents {
+                let moved = new_path.join(path.strip_prefix(old_path).unwrap());
+                let diffitem = diffs.remove(&path).unwrap();
+                let matching = match (
+                    index_ref.contents.get(&path),
+                    index_new.contents.get(&moved),
+                ) {
+                    (Some(old_item), Some(new_item)) => {
+                        old_item.ftype == new_item.ftype
+                            && (old_item.ftype != FileType::File || old_item.same_file(new_item))
+                    }
+                    _ => false,
+                };
+                if matching {
+                    // Moved along with the dir, nothing more to do
+                    diffs.remove(&moved);
+                    used.insert(moved);
+                } else if !index_new.contents.contains_key(&moved) {
+                    // Removed after the rename
+                    diffs.insert(moved, diffitem);
+                }
+                // Otherwise the entry was changed, and the added item under
+                // the new name will replace it after the rename.
+            }
+        }
+        let new = &index_new.contents[&new_path];
+        diffs.remove(old_path);
+        diffs.insert(
+            new_path,
+            DiffItem::new(ChangeType::Renamed(old_path.to_path_buf()), new),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(ftype: FileType, size: u64, mtime: i64, ino: u64) -> PathData {
+        PathData {
+            mtime,
+            mtime_nsec: 0,
+            perms: 0o644,
+            size,
+            ftype,
+            hash: None,
+            ino,
+            ctime: 0,
+            ctime_nsec: 0,
+        }
+    }
+
+    fn file(size: u64, mtime: i64, ino: u64) -> PathData {
+        entry(FileType::File, size, mtime, ino)
+    }
+
+    fn index(items: Vec<(&str, PathData)>) -> DirIndex {
+        DirIndex {
+            contents: items
+                .into_iter()
+                .map(|(path, pathdata)| (PathBuf::from(path), pathdata))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    // The diff between two indexes, without looking at what changed in the common entries
+    fn diff_of(index_ref: &DirIndex, index_new: &DirIndex) -> HashMap<PathBuf, DiffItem> {
+        let mut diff = HashMap::new();
+        for (path, old) in index_ref.contents.iter() {
+            if !index_new.contents.contains_key(path) {
+                diff.insert(path.clone(), DiffItem::new(ChangeType::RefOnly, old));
+            }
+        }
+        for (path, new) in index_new.contents.iter() {
+            match index_ref.contents.get(path) {
+                None => diff.insert(path.clone(), DiffItem::new(ChangeType::NewOnly, new)),
+                Some(old) if !old.same_file(new) && new.ftype == FileType::File => {
+                    diff.insert(path.clone(), DiffItem::new(ChangeType::Modified, new))
+                }
+                _ => None,
+            };
+        }
+        diff
+    }
+
+    fn renames(
+        index_ref: &DirIndex,
+        index_new: &DirIndex,
+        other: &[&str],
+    ) -> HashMap<PathBuf, DiffItem> {
+        let mut diff = diff_of(index_ref, index_new);
+        let diff_other = other
+            .iter()
+            .map(|path| {
+                (
+                    PathBuf::from(path),
+                    DiffItem::new(ChangeType::Newer, &file(1, 1, 0)),
+                )
+            })
+            .collect();
+        detect_renames(&mut diff, index_ref, index_new, &diff_other);
+        diff
+    }
+
+    fn change_of(diff: &HashMap<PathBuf, DiffItem>, path: &str) -> Option<ChangeType> {
+        diff.get(&PathBuf::from(path)).map(|d| d.diff.clone())
+    }
+
+    fn renamed_from(path: &str) -> Option<ChangeType> {
+        Some(ChangeType::Renamed(PathBuf::from(path)))
+    }
+
+    #[test]
+    fn file_renamed() {
+        let index_ref = index(vec![("a.txt", file(10, 100, 5))]);
+        // A copy with the same size and mtime doesn't hide the one with the same inode
+        let index_new = index(vec![
+            ("b.txt", file(10, 100, 5)),
+            ("c.txt", file(10, 100, 9)),
+        ]);
+        let diff = renames(&index_ref, &index_new, &[]);
+        assert_eq!(change_of(&diff, "b.txt"), renamed_from("a.txt"));
+        assert_eq!(change_of(&diff, "c.txt"), Some(ChangeType::NewOnly));
+        assert_eq!(change_of(&diff, "a.txt"), None);
+    }
+
+    #[test]
+    fn reused_inode() {
+        // The file was deleted, and its inode given to another one
+        let index_ref = index(vec![("a.txt", file(10, 100, 5))]);
+        let index_new = index(vec![("b.txt", file(20, 200, 5))]);
+        let diff = renames(&index_ref, &index_new, &[]);
+        assert_eq!(change_of(&diff, "a.txt"), Some(ChangeType::RefOnly));
+        assert_eq!(change_of(&diff, "b.txt"), Some(ChangeType::NewOnly));
+    }
+
+    #[test]
+    fn unique_size_and_mtime() {
+        // Moved to another file system, so the inode changed
+        let index_ref = index(vec![("a.txt", file(10, 100, 5))]);
+        let index_new = index(vec![
+            ("b.txt", file(10, 100, 7)),
+            ("c.txt", file(10, 200, 8)),
+        ]);
+        let diff = renames(&index_ref, &index_new, &[]);
+        assert_eq!(change_of(&diff, "b.txt"), renamed_from("a.txt"));
+        assert_eq!(change_of(&diff, "c.txt"), Some(ChangeType::NewOnly));
+        // Two candidates, it can't be told which one it is
+        let index_new = index(vec![
+            ("b.txt", file(10, 100, 7)),
+            ("c.txt", file(10, 100, 8)),
+        ]);
+        let diff = renames(&index_ref, &index_new, &[]);
+        assert_eq!(change_of(&diff, "a.txt"), Some(ChangeType::RefOnly));
+        assert_eq!(change_of(&diff, "b.txt"), Some(ChangeType::NewOnly));
+        assert_eq!(change_of(&diff, "c.txt"), Some(ChangeType::NewOnly));
+    }
+
+    #[test]
+    fn dir_renamed_with_changes_inside() {
+        let index_ref = index(vec![
+            ("d", entry(FileType::Dir, 0, 100, 1)),
+            ("d/same", file(10, 100, 2)),
+            ("d/edited", file(10, 100, 3)),
+            ("d/gone", file(10, 100, 4)),
+        ]);
+        let index_new = index(vec![
+            ("e", entry(FileType::Dir, 0, 200, 1)),
+            ("e/same", file(10, 100, 2)),
+            ("e/edited", file(20, 200, 3)),
+            ("e/added", file(10, 200, 6)),
+        ]);
+        let diff = renames(&index_ref, &index_new, &[]);
+        assert_eq!(change_of(&diff, "e"), renamed_from("d"));
+        assert_eq!(change_of(&diff, "e/same"), None);
+        assert_eq!(change_of(&diff, "e/edited"), Some(ChangeType::NewOnly));
+        assert_eq!(change_of(&diff, "e/gone"), Some(ChangeType::RefOnly));
+        assert_eq!(change_of(&diff, "e/added"), Some(ChangeType::NewOnly));
+        assert!(diff.keys().all(|path| !path.starts_with("d")));
+        assert_eq!(diff.len(), 4);
+    }
+
+    #[test]
+    fn skipped_if_the_other_side_changed_it() {
+        let index_ref = index(vec![
+            ("d", entry(FileType::Dir, 0, 100, 1)),
+            ("d/x", file(10, 100, 2)),
+        ]);
+        let index_new = index(vec![
+            ("e", entry(FileType::Dir, 0, 100, 1)),
+            ("e/x", file(10, 100, 2)),
+        ]);
+        assert_eq!(
+            change_of(&renames(&index_ref, &index_new, &[]), "e"),
+            renamed_from("d")
+        );
+        // Changed inside the old path, or created at the new one
+        for other in ["d/x", "e"].iter() {
+            let diff = renames(&index_ref, &index_new, &[other]);
+            assert_eq!(change_of(&diff, "d"), Some(ChangeType::RefOnly));
+            assert_eq!(change_of(&diff, "e"), Some(ChangeType::NewOnly));
+        }
+        let diff = renames(&index_ref, &index_new, &["d/x"]);
+        assert_eq!(change_of(&diff, "e/x"), Some(ChangeType::NewOnly));
+    }
+}
@@ -0,0 +1,325 @@
+use crate::datatypes::{DiffItem, DirIndex, SyncAction};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+pub const RETRYFILENAME: &str = ".twoway.retry.json";
+
+// Delay before the first retry, doubled for every failed attempt
+const RETRY_BASE_DELAY: u64 = 10;
+const RETRY_MAX_DELAY: u64 = 3600;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RetryItem {
+    pub action: SyncAction,
+    pub error: String,
+    pub attempts: u32,
+    pub next_attempt: u64,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct RetryQueue {
+    pub items: HashMap<PathBuf, RetryItem>,
+}
+
+fn backoff(attempts: u32) -> u64 {
+    let factor = 1u64
+        .checked_shl(attempts.saturating_sub(1))
+        .unwrap_or(u64::MAX);
+    RETRY_BASE_DELAY.saturating_mul(factor).min(RETRY_MAX_DELAY)
+}
+
+fn load_queue_file(jsonpath: &PathBuf) -> Result<RetryQueue, Box<dyn Error>> {
+    let mut jsonfile = File::open(jsonpath)?;
+    let mut contents = String::new();
+    jsonfile.read_to_string(&mut contents)?;
+    let queue: RetryQueue = serde_json::from_str(&contents)?;
+    Ok(queue)
+}
+
+fn save_queue_file(queue: &RetryQueue, jsonpath: &PathBuf) -> Result<(), Box<dyn Error>> {
+    if queue.items.is_empty() {
+        if fs::metadata(jsonpath).is_ok() {
+            fs::remove_file(jsonpath)?;
+        }
+        return Ok(());
+    }
+    let serialized = serde_json::to_string(queue)?;
+    let mut jsonfile = File::create(jsonpath)?;
+    jsonfile.write_all(serialized.as_bytes())?;
+    Ok(())
+}
+
+/// Copy the entries for `paths` from `source` into `target`,
+/// removing them from `target` if `source` doesn't have them.
+pub fn overlay_entries<'a, I>(target: &mut DirIndex, source: &DirIndex, paths: I)
+where
+    I: Iterator<Item = &'a PathBuf>,
+{
+    for path in paths {
+        match source.contents.get(path) {
+            Some(pathdata) => {
+                target.contents.insert(path.to_path_buf(), pathdata.clone());
+            }
+            None => {
+                target.contents.remove(path);
+            }
+        }
+    }
+}
+
+impl RetryQueue {
+    /// Load the queues of all roots and merge them.
+    pub fn load(files: &[PathBuf]) -> RetryQueue {
+        let mut queue = RetryQueue::default();
+        for path in files.iter() {
+            if let Ok(q) = load_queue_file(path) {
+                queue.items.extend(q.items);
+            }
+        }
+        queue
+    }
+
+    /// Save the queue, each item is stored in the file of the root it failed to modify.
+    pub fn save(&self, roots: &[PathBuf], files: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+        let mut queues = vec![RetryQueue::default(); roots.len()];
+        for (relpath, item) in self.items.iter() {
+            let idx = roots
+                .iter()
+                .position(|root| item.action.dest().starts_with(root))
+                .unwrap_or(0);
+            queues[idx]
+                .items
+                .insert(relpath.to_path_buf(), item.clone());
+        }
+        for (queue, file) in queues.iter().zip(files.iter()) {
+            save_queue_file(queue, file)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    /// Remove the diffs of paths that are waiting for their next retry.
+    pub fn hold_back(&self, diffs: &mut HashMap<PathBuf, DiffItem>, now: u64) {
+        for (relpath, item) in self.items.iter() {
+            if item.next_attempt > now {
+                diffs.remove(relpath);
+            }
+        }
+    }
+
+    /// Update the queue after a sync. Attempted paths without a new failure are
+    /// dropped, failed ones are added or rescheduled with an increasing delay.
+    pub fn update<'a, I>(
+        &mut self,
+        attempted: I,
+        failures: Vec<(SyncAction, String)>,
+        roots: &[PathBuf],
+        now: u64,
+    ) where
+        I: Iterator<Item = &'a PathBuf>,
+    {
+        let mut failed = HashMap::new();
+        for (action, error) in failures.into_iter() {
+            // Some actions, like renames, involve two different paths
+            let mut paths = vec![action.dest().to_path_buf()];
+            if let Some(src) = action.src() {
+                paths.push(src.to_path_buf());
+            }
+            for path in paths.iter() {
+                if let Some(relpath) = roots.iter().find_map(|root| path.strip_prefix(root).ok()) {
+                    failed
+                        .entry(relpath.to_path_buf())
+                        .or_insert((action.clone(), error.clone()));
+                }
+            }
+        }
+        for relpath in attempted {
+            if !failed.contains_key(relpath) {
+                self.items.remove(relpath);
+            }
+        }
+        for (relpath, (action, error)) in failed.into_iter() {
+            let attempts = match self.items.get(&relpath) {
+                Some(item) => item.attempts + 1,
+                None => 1,
+            };
+            self.items.insert(
+                relpath,
+                RetryItem {
+                    action,
+                    error,
+                    attempts,
+                    next_attempt: now + backoff(attempts),
+                },
+            );
+        }
+    }
+
+    /// Keep the previous index state for all queued paths, so that
+    /// the change is detected again on the next scan.
+    pub fn restore_entries(&self, index_new: &mut DirIndex, index_old: &DirIndex) {
+        overlay_entries(index_new, index_old, self.items.keys());
+    }
+
+    pub fn print(&self) {
+        for item in self.items.values() {
+            println!(
+                "Retry {} failed: {}, {}\r",
+                item.attempts, item.action, item.error
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatypes::{ChangeType, FileType, PathData};
+
+    fn entry(size: u64) -> PathData {
+        PathData {
+            mtime: 100,
+            mtime_nsec: 0,
+            perms: 0o644,
+            size,
+            ftype: FileType::File,
+            hash: None,
+            ino: 0,
+            ctime: 0,
+            ctime_nsec: 0,
+        }
+    }
+
+    fn roots() -> Vec<PathBuf> {
+        vec![PathBuf::from("/a"), PathBuf::from("/b")]
+    }
+
+    fn copy(path: &str) -> SyncAction {
+        SyncAction::CopyFile {
+            src: PathBuf::from("/a").join(path),
+            dest: PathBuf::from("/b").join(path),
+        }
+    }
+
+    fn failed(path: &str) -> (SyncAction, String) {
+        (copy(path), String::from("Permission denied"))
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_an_hour() {
+        let delays = (1..=11).map(backoff).collect::<Vec<u64>>();
+        assert_eq!(
+            delays,
+            vec![10, 20, 40, 80, 160, 320, 640, 1280, 2560, 3600, 3600]
+        );
+        assert_eq!(backoff(0), 10);
+        assert_eq!(backoff(200), 3600);
+    }
+
+    #[test]
+    fn failures_are_rescheduled() {
+        let mut queue = RetryQueue::default();
+        let attempted = [PathBuf::from("x"), PathBuf::from("y")];
+        queue.update(
+            attempted.iter(),
+            vec![failed("x"), failed("y")],
+            &roots(),
+            1000,
+        );
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.items[&PathBuf::from("x")].next_attempt, 1010);
+        // x fails again, y succeeds
+        queue.update(attempted.iter(), vec![failed("x")], &roots(), 1010);
+        assert_eq!(queue.len(), 1);
+        let item = &queue.items[&PathBuf::from("x")];
+        assert_eq!((item.attempts, item.next_attempt), (2, 1030));
+        // A rename that fails is retried for both paths
+        let rename = SyncAction::Rename {
+            src: PathBuf::from("/b/old"),
+            dest: PathBuf::from("/b/new"),
+        };
+        queue.update(
+            [].iter(),
+            vec![(rename, String::from("Busy"))],
+            &roots(),
+            1030,
+        );
+        assert!(queue.items.contains_key(&PathBuf::from("old")));
+        assert!(queue.items.contains_key(&PathBuf::from("new")));
+        // Paths that weren't attempted stay in the queue
+        assert!(queue.items.contains_key(&PathBuf::from("x")));
+    }
+
+    #[test]
+    fn waiting_paths_are_held_back() {
+        let mut queue = RetryQueue::default();
+        queue.update(
+            [PathBuf::from("x")].iter(),
+            vec![failed("x")],
+            &roots(),
+            1000,
+        );
+        let diff = || {
+            ["x", "y"]
+                .iter()
+                .map(|path| {
+                    (
+                        PathBuf::from(path),
+                        DiffItem::new(ChangeType::Newer, &entry(10)),
+                    )
+                })
+                .collect::<HashMap<PathBuf, DiffItem>>()
+        };
+        let mut diffs = diff();
+        queue.hold_back(&mut diffs, 1009);
+        assert!(!diffs.contains_key(&PathBuf::from("x")));
+        assert!(diffs.contains_key(&PathBuf::from("y")));
+        let mut diffs = diff();
+        queue.hold_back(&mut diffs, 1010);
+        assert_eq!(diffs.len(), 2);
+    }
+
+    #[test]
+    fn old_entries_are_kept_for_queued_paths() {
+        let mut queue = RetryQueue::default();
+        let attempted = [PathBuf::from("changed"), PathBuf::from("new")];
+        queue.update(
+            attempted.iter(),
+            vec![failed("changed"), failed("new")],
+            &roots(),
+            1000,
+        );
+        let mut index_old = DirIndex::default();
+        index_old
+            .contents
+            .insert(PathBuf::from("changed"), entry(10));
+        index_old
+            .contents
+            .insert(PathBuf::from("synced"), entry(10));
+        let mut index_new = DirIndex::default();
+        for path in ["changed", "new", "synced"].iter() {
+            index_new.contents.insert(PathBuf::from(path), entry(20));
+        }
+        queue.restore_entries(&mut index_new, &index_old);
+        // The failed change is detected again, and the failed copy of a new file too
+        assert_eq!(index_new.contents[&PathBuf::from("changed")].size, 10);
+        assert!(!index_new.contents.contains_key(&PathBuf::from("new")));
+        assert_eq!(index_new.contents[&PathBuf::from("synced")].size, 20);
+    }
+}
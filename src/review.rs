@@ -0,0 +1,271 @@
+use crate::conflicts::{root_name, Conflict, Decision};
+use chrono::{DateTime, Local};
+use similar::TextDiff;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use termion::event::Key;
+use termion::{clear, cursor};
+
+const HELP: &str = "Up/Down to select, A or B to keep that version, K to keep both, S to skip, \
+                    D to show the differences.\r\nShift+A/B/K/S applies to all conflicts. \
+                    Enter applies the decisions, Esc cancels.";
+
+// Some terminals report a size of zero
+fn screen_rows() -> usize {
+    match termion::terminal_size() {
+        Ok((_, rows)) if rows > 0 => rows as usize,
+        _ => 24,
+    }
+}
+
+fn decision_name(decision: Option<Decision>, conflict: &Conflict) -> &'static str {
+    match decision {
+        Some(Decision::Keep(root)) if root == conflict.sides().0 => "keep A",
+        Some(Decision::Keep(_)) => "keep B",
+        Some(Decision::KeepBoth) => "keep both",
+        Some(Decision::Skip) => "skip",
+        None => "",
+    }
+}
+
+// Only files can be kept under a conflict name, not an item that is a file on one
+// side and a directory or link on the other
+fn types_differ(conflict: &Conflict, roots: &[PathBuf]) -> bool {
+    let (first, second) = conflict.sides();
+    match (
+        fs::symlink_metadata(roots[first].join(&conflict.path)),
+        fs::symlink_metadata(roots[second].join(&conflict.path)),
+    ) {
+        (Ok(a), Ok(b)) => a.file_type() != b.file_type(),
+        _ => false,
+    }
+}
+
+fn perms_string(mode: u32) -> String {
+    let mut perms = String::new();
+    for shift in [6, 3, 0].iter() {
+        let bits = (mode >> shift) & 0o7;
+        perms.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        perms.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        perms.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    perms
+}
+
+// One line with the size, modification time and permissions of one side
+fn describe(side: &str, path: &PathBuf) -> String {
+    match fs::symlink_metadata(path) {
+        Ok(meta) => {
+            let mtime = meta
+                .modified()
+                .map(|t| {
+                    DateTime::<Local>::from(t)
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string()
+                })
+                .unwrap_or_default();
+            format!(
+                "{}: {:>12} bytes  {}  {}",
+                side,
+                meta.len(),
+                mtime,
+                perms_string(meta.permissions().mode())
+            )
+        }
+        Err(e) => format!("{}: {}", side, e),
+    }
+}
+
+// With more than two directories, A and B are the first and second of the two that conflict
+fn side_label(key: char, root: usize, roots: &[PathBuf]) -> String {
+    if roots.len() == 2 {
+        key.to_string()
+    } else {
+        format!("{} ({})", key, roots[root].display())
+    }
+}
+
+fn text_diff(conflict: &Conflict, roots: &[PathBuf]) -> Vec<String> {
+    let (first, second) = conflict.sides();
+    let contents = (
+        fs::read(roots[first].join(&conflict.path)),
+        fs::read(roots[second].join(&conflict.path)),
+    );
+    let (data_a, data_b) = match contents {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(e), _) | (_, Err(e)) => return vec![format!("Unable to read file: {}", e)],
+    };
+    match (String::from_utf8(data_a), String::from_utf8(data_b)) {
+        (Ok(text_a), Ok(text_b)) => {
+            let name = conflict.path.display().to_string();
+            TextDiff::from_lines(&text_a, &text_b)
+                .unified_diff()
+                .header(
+                    &format!("{}/{}", root_name(first), name),
+                    &format!("{}/{}", root_name(second), name),
+                )
+                .to_string()
+                .lines()
+                .map(|line| line.to_string())
+                .collect()
+        }
+        _ => vec![String::from("Binary files differ")],
+    }
+}
+
+fn show_pager<K, W>(lines: &[String], keys: &mut K, out: &mut W) -> Result<(), Box<dyn Error>>
+where
+    K: Iterator<Item = Result<Key, io::Error>>,
+    W: Write,
+{
+    let height = screen_rows().saturating_sub(2).max(1);
+    let mut offset = 0;
+    loop {
+        write!(out, "{}{}", clear::All, cursor::Goto(1, 1))?;
+        for line in lines.iter().skip(offset).take(height) {
+            write!(out, "{}\r\n", line)?;
+        }
+        write!(
+            out,
+            "-- lines {}-{} of {}, Up/Down/Space to scroll, Q to go back --",
+            offset + 1,
+            (offset + height).min(lines.len()),
+            lines.len()
+        )?;
+        out.flush()?;
+        let last = lines.len().saturating_sub(height);
+        match keys.next() {
+            Some(Ok(Key::Down)) | Some(Ok(Key::Char('j'))) => offset = (offset + 1).min(last),
+            Some(Ok(Key::Up)) | Some(Ok(Key::Char('k'))) => offset = offset.saturating_sub(1),
+            Some(Ok(Key::Char(' '))) | Some(Ok(Key::PageDown)) => {
+                offset = (offset + height).min(last)
+            }
+            Some(Ok(Key::PageUp)) => offset = offset.saturating_sub(height),
+            Some(Ok(Key::Char('q'))) | Some(Ok(Key::Esc)) | None => return Ok(()),
+            Some(Err(e)) => return Err(Box::new(e)),
+            _ => {}
+        }
+    }
+}
+
+/// Show the conflicts waiting for a decision and let the user choose how to resolve them.
+/// Returns the decisions, empty if the review was cancelled.
+pub fn review_conflicts<K, W>(
+    conflicts: &[Conflict],
+    roots: &[PathBuf],
+    keys: &mut K,
+    out: &mut W,
+) -> Result<HashMap<PathBuf, Decision>, Box<dyn Error>>
+where
+    K: Iterator<Item = Result<Key, io::Error>>,
+    W: Write,
+{
+    let mut decisions: Vec<Option<Decision>> = vec![None; conflicts.len()];
+    let mut selected = 0;
+    let mut offset = 0;
+    let mut message = "";
+    loop {
+        let height = screen_rows().saturating_sub(9).max(3);
+        if selected < offset {
+            offset = selected;
+        } else if selected >= offset + height {
+            offset = selected + 1 - height;
+        }
+        write!(out, "{}{}", clear::All, cursor::Goto(1, 1))?;
+        write!(
+            out,
+            "{} conflicts waiting for a decision\r\n\r\n",
+            conflicts.len()
+        )?;
+        for (idx, conflict) in conflicts.iter().enumerate().skip(offset).take(height) {
+            write!(
+                out,
+                "{} [{:<9}] {}\r\n",
+                if idx == selected { ">" } else { " " },
+                decision_name(decisions[idx], conflict),
+                conflict.path.display()
+            )?;
+        }
+        let conflict = &conflicts[selected];
+        let (first, second) = conflict.sides();
+        write!(
+            out,
+            "\r\n{}\r\n{}\r\n\r\n{}\r\n{}\r\n",
+            describe(
+                &side_label('A', first, roots),
+                &roots[first].join(&conflict.path)
+            ),
+            describe(
+                &side_label('B', second, roots),
+                &roots[second].join(&conflict.path)
+            ),
+            HELP,
+            message
+        )?;
+        message = "";
+        out.flush()?;
+        let key = match keys.next() {
+            Some(key) => key?,
+            None => return Ok(HashMap::new()),
+        };
+        // The version to keep is chosen by its position among the two shown, A or B
+        let (choice, all): (fn(&Conflict) -> Decision, bool) = match key {
+            Key::Up => {
+                selected = selected.saturating_sub(1);
+                continue;
+            }
+            Key::Down => {
+                selected = (selected + 1).min(conflicts.len() - 1);
+                continue;
+            }
+            Key::Char('d') => {
+                let lines = text_diff(conflict, roots);
+                show_pager(&lines, keys, out)?;
+                continue;
+            }
+            Key::Char('\n') => break,
+            Key::Esc | Key::Char('q') => {
+                write!(out, "{}{}", clear::All, cursor::Goto(1, 1))?;
+                return Ok(HashMap::new());
+            }
+            Key::Char('a') => (|c| Decision::Keep(c.sides().0), false),
+            Key::Char('b') => (|c| Decision::Keep(c.sides().1), false),
+            Key::Char('k') => (|_| Decision::KeepBoth, false),
+            Key::Char('s') => (|_| Decision::Skip, false),
+            Key::Char('A') => (|c| Decision::Keep(c.sides().0), true),
+            Key::Char('B') => (|c| Decision::Keep(c.sides().1), true),
+            Key::Char('K') => (|_| Decision::KeepBoth, true),
+            Key::Char('S') => (|_| Decision::Skip, true),
+            _ => continue,
+        };
+        let allowed = |idx: usize| {
+            choice(&conflicts[idx]) != Decision::KeepBoth || !types_differ(&conflicts[idx], roots)
+        };
+        if all {
+            for (idx, decision) in decisions.iter_mut().enumerate() {
+                if allowed(idx) {
+                    *decision = Some(choice(&conflicts[idx]));
+                } else {
+                    message =
+                        "Both can't be kept when one of them isn't a file, choose A or B for those";
+                }
+            }
+        } else if allowed(selected) {
+            decisions[selected] = Some(choice(conflict));
+            selected = (selected + 1).min(conflicts.len() - 1);
+        } else {
+            message = "Both can't be kept when one of them isn't a file, choose A or B";
+        }
+    }
+    write!(out, "{}{}", clear::All, cursor::Goto(1, 1))?;
+    Ok(conflicts
+        .iter()
+        .zip(decisions)
+        .filter_map(|(conflict, decision)| decision.map(|d| (conflict.path.clone(), d)))
+        .collect())
+}
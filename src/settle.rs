@@ -0,0 +1,232 @@
+use crate::datatypes::{ChangeType, DiffItem, FileType};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+// Access mode bits of the file flags, anything but O_RDONLY means writing
+const O_ACCMODE: u32 = 0o3;
+
+/// Keeps track of changed files until they have stopped changing.
+pub struct SettleTracker {
+    quiet: u64,
+    // Size and mtime of each changing file, and when they were first seen
+    seen: HashMap<PathBuf, (u64, i128, u64)>,
+    pub held: HashSet<PathBuf>,
+}
+
+fn is_copied_file(diffitem: &DiffItem) -> bool {
+    diffitem.ftype == FileType::File
+        && match diffitem.diff {
+            ChangeType::Newer
+            | ChangeType::Older
+            | ChangeType::Modified
+            | ChangeType::NewOnly
+            | ChangeType::TypeChanged => true,
+            ChangeType::RefOnly | ChangeType::Renamed(_) => false,
+        }
+}
+
+fn open_flags(fdinfo: &PathBuf) -> Option<u32> {
+    let info = fs::read_to_string(fdinfo).ok()?;
+    let flags = info.lines().find(|line| line.starts_with("flags:"))?;
+    u32::from_str_radix(flags.trim_start_matches("flags:").trim(), 8).ok()
+}
+
+/// Find the files that some process has open for writing, by looking through /proc.
+/// Only the processes we are allowed to inspect are found, and nothing on systems without /proc.
+pub fn open_for_writing(files: &HashSet<PathBuf>) -> HashSet<PathBuf> {
+    let mut found = HashSet::new();
+    if files.is_empty() {
+        return found;
+    }
+    let procs = match fs::read_dir("/proc") {
+        Ok(procs) => procs,
+        Err(_) => return found,
+    };
+    for process in procs.flatten() {
+        if !process
+            .file_name()
+            .to_string_lossy()
+            .bytes()
+            .all(|c| c.is_ascii_digit())
+        {
+            continue;
+        }
+        let fds = match fs::read_dir(process.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.flatten() {
+            let target = match fs::read_link(fd.path()) {
+                Ok(target) => target,
+                Err(_) => continue,
+            };
+            if !files.contains(&target) || found.contains(&target) {
+                continue;
+            }
+            let fdinfo = process.path().join("fdinfo").join(fd.file_name());
+            if let Some(flags) = open_flags(&fdinfo) {
+                if flags & O_ACCMODE != 0 {
+                    found.insert(target);
+                }
+            }
+        }
+    }
+    found
+}
+
+impl SettleTracker {
+    pub fn new(quiet: u64) -> SettleTracker {
+        SettleTracker {
+            quiet,
+            seen: HashMap::new(),
+            held: HashSet::new(),
+        }
+    }
+
+    /// Remove changed files from the diffs of all roots until their size and mtime have
+    /// been stable for the quiet period, and nobody has them open for writing.
+    /// Returns the paths that started being held back in this call.
+    pub fn hold_back(
+        &mut self,
+        diffs: &mut [HashMap<PathBuf, DiffItem>],
+        roots: &[PathBuf],
+        now: u64,
+    ) -> Vec<PathBuf> {
+        if self.quiet == 0 {
+            return Vec::new();
+        }
+        let mut changing = HashSet::new();
+        let mut candidates = HashMap::new();
+        for (root, diff) in roots.iter().zip(diffs.iter()) {
+            for (path, diffitem) in diff.iter().filter(|(_, d)| is_copied_file(d)) {
+                let fullpath = root.join(path);
+                let since = match self.seen.get(&fullpath) {
+                    Some((size, mtime, since))
+                        if *size == diffitem.size && *mtime == diffitem.mtime =>
+                    {
+                        *since
+                    }
+                    _ => now,
+                };
+                self.seen
+                    .insert(fullpath.clone(), (diffitem.size, diffitem.mtime, since));
+                if now - since < self.quiet {
+                    changing.insert(path.to_path_buf());
+                }
+                candidates.insert(fullpath, path.to_path_buf());
+            }
+        }
+        let files = candidates.keys().cloned().collect::<HashSet<PathBuf>>();
+        for fullpath in open_for_writing(&files).iter() {
+            changing.insert(candidates[fullpath].clone());
+        }
+        self.seen.retain(|fullpath, _| {
+            candidates
+                .get(fullpath)
+                .is_some_and(|path| changing.contains(path))
+        });
+        for diff in diffs.iter_mut() {
+            diff.retain(|path, _| !changing.contains(path));
+        }
+        let new_held = changing
+            .iter()
+            .filter(|path| !self.held.contains(*path))
+            .cloned()
+            .collect();
+        self.held = changing;
+        new_held
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changed(size: u64, mtime: i128) -> DiffItem {
+        DiffItem {
+            diff: ChangeType::Modified,
+            ftype: FileType::File,
+            mtime,
+            size,
+            hash: None,
+        }
+    }
+
+    fn diffs(item: DiffItem, other: Option<DiffItem>) -> Vec<HashMap<PathBuf, DiffItem>> {
+        let mut diffs = vec![HashMap::new(), HashMap::new()];
+        diffs[0].insert(PathBuf::from("file"), item);
+        if let Some(other) = other {
+            diffs[1].insert(PathBuf::from("file"), other);
+        }
+        diffs
+    }
+
+    fn roots() -> Vec<PathBuf> {
+        vec![
+            PathBuf::from("/nonexistent/a"),
+            PathBuf::from("/nonexistent/b"),
+        ]
+    }
+
+    #[test]
+    fn held_for_the_quiet_period() {
+        let mut tracker = SettleTracker::new(2);
+        let mut d = diffs(changed(10, 0), None);
+        assert_eq!(
+            tracker.hold_back(&mut d, &roots(), 100),
+            vec![PathBuf::from("file")]
+        );
+        assert!(d[0].is_empty());
+        // Still held, but not reported again
+        let mut d = diffs(changed(10, 0), None);
+        assert!(tracker.hold_back(&mut d, &roots(), 101).is_empty());
+        assert!(d[0].is_empty());
+        assert!(tracker.held.contains(&PathBuf::from("file")));
+        let mut d = diffs(changed(10, 0), None);
+        tracker.hold_back(&mut d, &roots(), 102);
+        assert_eq!(d[0].len(), 1);
+        assert!(tracker.held.is_empty());
+    }
+
+    #[test]
+    fn changes_restart_the_wait() {
+        let mut tracker = SettleTracker::new(2);
+        let mut d = diffs(changed(10, 0), None);
+        tracker.hold_back(&mut d, &roots(), 100);
+        // It grew, and then its mtime changed
+        let mut d = diffs(changed(20, 0), None);
+        tracker.hold_back(&mut d, &roots(), 101);
+        let mut d = diffs(changed(20, 5), None);
+        tracker.hold_back(&mut d, &roots(), 102);
+        assert!(d[0].is_empty());
+        let mut d = diffs(changed(20, 5), None);
+        tracker.hold_back(&mut d, &roots(), 103);
+        assert!(d[0].is_empty());
+        let mut d = diffs(changed(20, 5), None);
+        tracker.hold_back(&mut d, &roots(), 104);
+        assert_eq!(d[0].len(), 1);
+    }
+
+    #[test]
+    fn other_side_waits_too() {
+        let mut tracker = SettleTracker::new(2);
+        let mut removed = changed(10, 0);
+        removed.diff = ChangeType::RefOnly;
+        let mut d = diffs(changed(10, 0), Some(removed.clone()));
+        tracker.hold_back(&mut d, &roots(), 100);
+        assert!(d[0].is_empty());
+        assert!(d[1].is_empty());
+        let mut d = diffs(changed(10, 0), Some(removed));
+        tracker.hold_back(&mut d, &roots(), 102);
+        assert_eq!((d[0].len(), d[1].len()), (1, 1));
+    }
+
+    #[test]
+    fn no_quiet_period() {
+        let mut tracker = SettleTracker::new(0);
+        let mut d = diffs(changed(10, 0), None);
+        assert!(tracker.hold_back(&mut d, &roots(), 100).is_empty());
+        assert_eq!(d[0].len(), 1);
+    }
+}
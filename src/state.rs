@@ -0,0 +1,367 @@
+use crate::conflicts::root_name;
+use crate::datatypes::{DirIndex, PairIdentity, RootId};
+use crate::retry::RETRYFILENAME;
+use crate::INDEXFILENAME;
+use filetime::FileTime;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh3::xxh3_64;
+
+// Lists the directories of a group, to tell the state directories apart
+const ROOTSFILENAME: &str = "roots.txt";
+
+/// The state is kept in $XDG_STATE_HOME, or in ~/.local/state if that isn't set.
+pub fn default_state_dir() -> PathBuf {
+    let state_dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").unwrap_or_default()).join(".local/state"),
+    };
+    state_dir.join("twowaysync")
+}
+
+// The same directories in the same order always give the same key
+fn group_key(roots: &[PathBuf]) -> String {
+    let joined = roots
+        .iter()
+        .map(|root| root.to_string_lossy().to_string())
+        .collect::<Vec<String>>()
+        .join("\n");
+    format!("{:016x}", xxh3_64(joined.as_bytes()))
+}
+
+/// The files with the index and the retry queue of each directory of a pair or group.
+/// They are kept in a directory of their own for each group, or in the synced directories
+/// themselves in the legacy mode.
+#[derive(Clone)]
+pub struct StateFiles {
+    pub dir: Option<PathBuf>,
+    pub indexes: Vec<PathBuf>,
+    pub retries: Vec<PathBuf>,
+}
+
+impl StateFiles {
+    pub fn in_tree(roots: &[PathBuf]) -> StateFiles {
+        StateFiles {
+            dir: None,
+            indexes: roots.iter().map(|root| root.join(INDEXFILENAME)).collect(),
+            retries: roots.iter().map(|root| root.join(RETRYFILENAME)).collect(),
+        }
+    }
+
+    pub fn in_state_dir(state_dir: &Path, roots: &[PathBuf]) -> StateFiles {
+        let dir = state_dir.join(group_key(roots));
+        let names = (0..roots.len()).map(root_name);
+        StateFiles {
+            indexes: names
+                .clone()
+                .map(|name| dir.join(format!("index-{}.json", name)))
+                .collect(),
+            retries: names
+                .map(|name| dir.join(format!("retry-{}.json", name)))
+                .collect(),
+            dir: Some(dir),
+        }
+    }
+
+    /// Create the state directory of the group.
+    pub fn create(&self, roots: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = &self.dir {
+            fs::create_dir_all(dir)?;
+            let listing = roots
+                .iter()
+                .map(|root| format!("{}\n", root.display()))
+                .collect::<String>();
+            fs::write(dir.join(ROOTSFILENAME), listing)?;
+        }
+        Ok(())
+    }
+
+    /// Move indexes and retry queues that are stored in the synced directories to the state
+    /// directory. This is only done if all the directories have an index, and the group
+    /// doesn't have any indexes yet. A file that can't be removed is left where it is.
+    pub fn migrate(&self, roots: &[PathBuf]) -> Result<bool, Box<dyn Error>> {
+        let legacy = StateFiles::in_tree(roots);
+        if self.dir.is_none()
+            || self.indexes.iter().any(|file| fs::metadata(file).is_ok())
+            || legacy
+                .indexes
+                .iter()
+                .any(|file| fs::metadata(file).is_err())
+        {
+            return Ok(false);
+        }
+        self.create(roots)?;
+        let files = legacy
+            .indexes
+            .iter()
+            .zip(self.indexes.iter())
+            .chain(legacy.retries.iter().zip(self.retries.iter()));
+        for (old, new) in files {
+            if fs::metadata(old).is_err() {
+                continue;
+            }
+            fs::copy(old, new)?;
+            // The time an index was saved tells which sync saved it
+            let mtime = FileTime::from_last_modification_time(&fs::metadata(old)?);
+            filetime::set_file_mtime(new, mtime)?;
+            if let Err(e) = fs::remove_file(old) {
+                println!("Unable to remove {}: {}\r", old.display(), e);
+            }
+        }
+        Ok(true)
+    }
+}
+
+// The UUID of the file system on a block device, found through the links in /dev/disk/by-uuid.
+// Network file systems have none.
+fn filesystem_uuid(device: u64) -> Option<String> {
+    fs::read_dir("/dev/disk/by-uuid")
+        .ok()?
+        .flatten()
+        .find(|entry| fs::metadata(entry.path()).is_ok_and(|meta| meta.rdev() == device))
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+}
+
+/// The directories as they are now. A directory that has been replaced, for example by
+/// restoring it from a backup, has a new inode, and another drive mounted in its place
+/// has another file system UUID.
+pub fn root_ids(roots: &[PathBuf]) -> Result<Vec<RootId>, Box<dyn Error>> {
+    roots
+        .iter()
+        .map(|root| {
+            let meta = fs::metadata(root)?;
+            Ok(RootId {
+                path: root.to_path_buf(),
+                filesystem: filesystem_uuid(meta.dev()),
+                inode: meta.ino(),
+            })
+        })
+        .collect()
+}
+
+/// Check that the directories are the same as when the identity was made.
+/// Returns the reason if they aren't.
+pub fn check_roots(known: &[RootId], current: &[RootId]) -> Result<(), String> {
+    for (old, new) in known.iter().zip(current.iter()) {
+        if let (Some(old_fs), Some(new_fs)) = (&old.filesystem, &new.filesystem) {
+            if old_fs != new_fs {
+                return Err(format!(
+                    "{} is on another file system than at the last sync",
+                    new.path.display()
+                ));
+            }
+        }
+        if old.inode != new.inode {
+            return Err(format!(
+                "{} has been replaced since the last sync",
+                new.path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// A random version 4 UUID
+fn new_uuid() -> Result<String, Box<dyn Error>> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+
+/// A new identity for the indexes of a pair or group.
+pub fn new_identity(roots: &[PathBuf]) -> Result<PairIdentity, Box<dyn Error>> {
+    Ok(PairIdentity {
+        id: new_uuid()?,
+        roots: root_ids(roots)?,
+    })
+}
+
+/// The times the index files were last saved, in seconds.
+pub fn save_times(files: &[PathBuf]) -> Vec<u64> {
+    files
+        .iter()
+        .map(|file| {
+            fs::metadata(file)
+                .map(|meta| FileTime::from_last_modification_time(&meta).unix_seconds() as u64)
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// True if the indexes are from an older version without identities, and they look like they
+/// were saved by the same sync. They must have been made for these directories, and all the
+/// scans must have started before any of the indexes was saved, since a sync scans all the
+/// directories before saving. Indexes saved by different syncs fail this, however long the
+/// scans took.
+pub fn saved_together(indexes: &[DirIndex], roots: &[PathBuf], saved: &[u64]) -> bool {
+    let first_saved = match saved.iter().min() {
+        Some(first_saved) => *first_saved,
+        None => return false,
+    };
+    indexes.len() == roots.len()
+        && indexes.iter().zip(roots.iter()).all(|(index, root)| {
+            index.pair.is_none() && &index.root == root && index.scantime <= first_saved
+        })
+}
+
+/// Check that the indexes were saved together, by a sync of these directories.
+/// Returns the reason if they weren't.
+pub fn check_identity(indexes: &[DirIndex], current: &[RootId]) -> Result<(), String> {
+    let first = indexes.first().and_then(|index| index.pair.as_ref());
+    for (idx, index) in indexes.iter().enumerate() {
+        let pair = match (&index.pair, first) {
+            (Some(pair), Some(first)) if pair.id == first.id => pair,
+            (Some(_), Some(_)) => {
+                return Err(format!(
+                    "the indexes of {} and {} belong to different pairs",
+                    root_name(0),
+                    root_name(idx)
+                ))
+            }
+            _ => return Err(format!("the index of {} has no pair id", root_name(idx))),
+        };
+        let paths_match = pair.roots.len() == current.len()
+            && pair
+                .roots
+                .iter()
+                .zip(current.iter())
+                .all(|(old, new)| old.path == new.path);
+        if !paths_match {
+            return Err(format!(
+                "the index of {} was made for other directories",
+                root_name(idx)
+            ));
+        }
+    }
+    match first {
+        Some(first) => check_roots(&first.roots, current),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_have_separate_state() {
+        let state_dir = PathBuf::from("/state");
+        let roots = vec![PathBuf::from("/a"), PathBuf::from("/b")];
+        let other = vec![PathBuf::from("/a"), PathBuf::from("/c")];
+        let files = StateFiles::in_state_dir(&state_dir, &roots);
+        let other_files = StateFiles::in_state_dir(&state_dir, &other);
+        assert_ne!(files.dir, other_files.dir);
+        assert_eq!(files.dir, StateFiles::in_state_dir(&state_dir, &roots).dir);
+        let dir = files.dir.unwrap();
+        assert!(dir.starts_with(&state_dir));
+        assert_eq!(files.indexes[1], dir.join("index-B.json"));
+        assert_eq!(files.retries[0], dir.join("retry-A.json"));
+    }
+
+    fn index(id: &str, roots: &[RootId]) -> DirIndex {
+        DirIndex {
+            pair: Some(PairIdentity {
+                id: id.to_string(),
+                roots: roots.to_vec(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn legacy_indexes() {
+        let roots = vec![PathBuf::from("/a"), PathBuf::from("/b")];
+        let legacy = |root: &str, scantime| DirIndex {
+            root: PathBuf::from(root),
+            scantime,
+            ..Default::default()
+        };
+        assert!(saved_together(
+            &[legacy("/a", 1000), legacy("/b", 1002)],
+            &roots,
+            &[1010, 1010]
+        ));
+        // A large directory that took an hour to scan
+        assert!(saved_together(
+            &[legacy("/a", 1000), legacy("/b", 4600)],
+            &roots,
+            &[4700, 4701]
+        ));
+        // Saved by syncs with different partners
+        assert!(!saved_together(
+            &[legacy("/a", 1000), legacy("/b", 5000)],
+            &roots,
+            &[1010, 5010]
+        ));
+        assert!(!saved_together(
+            &[legacy("/a", 1000), legacy("/c", 1000)],
+            &roots,
+            &[1010, 1010]
+        ));
+    }
+
+    #[test]
+    fn identity_mismatch() {
+        let root = |path: &str, filesystem: Option<&str>, inode| RootId {
+            path: PathBuf::from(path),
+            filesystem: filesystem.map(|uuid| uuid.to_string()),
+            inode,
+        };
+        let roots = vec![root("/a", Some("u1"), 1), root("/b", Some("u2"), 2)];
+        let pair = vec![index("x", &roots), index("x", &roots)];
+        assert_eq!(check_identity(&pair, &roots), Ok(()));
+        let other = vec![index("x", &roots), index("y", &roots)];
+        assert_eq!(
+            check_identity(&other, &roots),
+            Err(String::from(
+                "the indexes of A and B belong to different pairs"
+            ))
+        );
+        let partner = vec![root("/a", Some("u1"), 1), root("/c", Some("u2"), 3)];
+        let moved = vec![index("x", &roots), index("x", &partner)];
+        assert_eq!(
+            check_identity(&moved, &roots),
+            Err(String::from(
+                "the index of B was made for other directories"
+            ))
+        );
+        let restored = vec![root("/a", Some("u1"), 1), root("/b", Some("u2"), 4)];
+        assert_eq!(
+            check_identity(&pair, &restored),
+            Err(String::from("/b has been replaced since the last sync"))
+        );
+        // Another drive mounted at the same place, with a root that has the same inode
+        let swapped = vec![root("/a", Some("u1"), 1), root("/b", Some("u3"), 2)];
+        assert_eq!(
+            check_identity(&pair, &swapped),
+            Err(String::from(
+                "/b is on another file system than at the last sync"
+            ))
+        );
+        // A network share has no UUID, and only the inode tells
+        let share = vec![root("/a", Some("u1"), 1), root("/b", None, 2)];
+        assert_eq!(check_identity(&pair, &share), Ok(()));
+        let legacy = vec![DirIndex::default(), index("x", &roots)];
+        assert_eq!(
+            check_identity(&legacy, &roots),
+            Err(String::from("the index of A has no pair id"))
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use chrono::{Local, NaiveDateTime, TimeZone};
+use std::error::Error;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+pub const TRASHDIRNAME: &str = ".twoway-trash";
+
+const STAMPFORMAT: &str = "%Y%m%d-%H%M%S";
+
+#[derive(Clone, Copy)]
+pub struct TrashConfig {
+    pub max_age_days: Option<u64>,
+    pub max_size_mb: Option<u64>,
+}
+
+/// Moves deleted and overwritten items to `<root>/.twoway-trash/<timestamp>/<relpath>`.
+/// One Trash is created per sync, so all items from one sync share the same timestamp.
+pub struct Trash {
+    roots: Vec<PathBuf>,
+    stamp: String,
+}
+
+fn trash_dir(root: &Path) -> PathBuf {
+    root.join(TRASHDIRNAME)
+}
+
+impl Trash {
+    pub fn new(roots: &[PathBuf]) -> Trash {
+        Trash {
+            roots: roots.to_vec(),
+            stamp: Local::now().format(STAMPFORMAT).to_string(),
+        }
+    }
+
+    fn trash_path(&self, path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        for root in self.roots.iter() {
+            if let Ok(relpath) = path.strip_prefix(root) {
+                let mut dest = trash_dir(root);
+                dest.push(&self.stamp);
+                dest.push(relpath);
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                return Ok(dest);
+            }
+        }
+        Err(From::from(format!(
+            "{} is outside the synced directories",
+            path.display()
+        )))
+    }
+
+    /// Move an item that is about to be deleted to the trash.
+    pub fn stash(&self, path: &PathBuf) -> Result<(), Box<dyn Error>> {
+        let dest = self.trash_path(path)?;
+        fs::rename(path, dest)?;
+        Ok(())
+    }
+
+    /// Keep the current version of a file that is about to be replaced.
+    /// The file stays in place, the trash gets a hard link to the old contents.
+    pub fn keep_version(&self, path: &PathBuf) -> Result<(), Box<dyn Error>> {
+        let dest = self.trash_path(path)?;
+        if fs::hard_link(path, &dest).is_err() {
+            fs::copy(path, &dest)?;
+        }
+        Ok(())
+    }
+}
+
+fn stamp_dirs(root: &Path) -> Vec<(String, PathBuf)> {
+    let mut stamps = Vec::new();
+    if let Ok(entries) = fs::read_dir(trash_dir(root)) {
+        for entry in entries.flatten() {
+            if let Some(name) = entry.file_name().to_str() {
+                if NaiveDateTime::parse_from_str(name, STAMPFORMAT).is_ok() {
+                    stamps.push((name.to_string(), entry.path()));
+                }
+            }
+        }
+    }
+    stamps.sort();
+    stamps
+}
+
+fn dir_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|m| !m.is_dir())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Remove the oldest trash entries until the age and size limits are respected.
+pub fn purge(root: &Path, config: &TrashConfig) -> Result<(), Box<dyn Error>> {
+    let mut stamps = stamp_dirs(root);
+    if let Some(days) = config.max_age_days {
+        let now = Local::now();
+        stamps.retain(|(stamp, path)| {
+            let age = NaiveDateTime::parse_from_str(stamp, STAMPFORMAT)
+                .ok()
+                .and_then(|t| Local.from_local_datetime(&t).earliest())
+                .map(|t| now.signed_duration_since(t).num_seconds())
+                .unwrap_or(0);
+            if age > (days * 86400) as i64 {
+                println!("Purging trash {}\r", path.display());
+                fs::remove_dir_all(path).is_err()
+            } else {
+                true
+            }
+        });
+    }
+    if let Some(mb) = config.max_size_mb {
+        let max_size = mb * 1024 * 1024;
+        let sizes = stamps
+            .iter()
+            .map(|(_, path)| dir_size(path))
+            .collect::<Vec<u64>>();
+        let mut total: u64 = sizes.iter().sum();
+        for ((_, path), size) in stamps.iter().zip(sizes.iter()) {
+            if total <= max_size {
+                break;
+            }
+            println!("Purging trash {}\r", path.display());
+            fs::remove_dir_all(path)?;
+            total -= size;
+        }
+    }
+    Ok(())
+}
+
+/// Check that a path to restore stays inside the trash and the synced directory.
+pub fn check_relpath(relpath: &Path) -> Result<(), Box<dyn Error>> {
+    if relpath.as_os_str().is_empty()
+        || !relpath
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(From::from(format!(
+            "{} is not a path inside the synced directory",
+            relpath.display()
+        )));
+    }
+    Ok(())
+}
+
+/// List the versions of a path found in the trash, oldest first.
+pub fn list_versions(root: &Path, relpath: &Path) -> Vec<String> {
+    stamp_dirs(root)
+        .into_iter()
+        .filter(|(_, path)| fs::symlink_metadata(path.join(relpath)).is_ok())
+        .map(|(stamp, _)| stamp)
+        .collect()
+}
+
+/// Move a path back from the trash. If the path exists, the current
+/// version is moved to the trash first. Without a version the newest one is used.
+pub fn restore(root: &Path, relpath: &Path, version: Option<&str>) -> Result<(), Box<dyn Error>> {
+    check_relpath(relpath)?;
+    let versions = list_versions(root, relpath);
+    let stamp = match version {
+        Some(v) => match versions.iter().find(|stamp| stamp.as_str() == v) {
+            Some(stamp) => stamp.to_string(),
+            None => {
+                return Err(From::from(format!(
+                    "No version {} of {} in the trash",
+                    v,
+                    relpath.display()
+                )))
+            }
+        },
+        None => match versions.last() {
+            Some(stamp) => stamp.to_string(),
+            None => {
+                return Err(From::from(format!(
+                    "{} was not found in the trash",
+                    relpath.display()
+                )))
+            }
+        },
+    };
+    let source = trash_dir(root).join(&stamp).join(relpath);
+    let trash = Trash::new(&[root.to_path_buf()]);
+    for direntry in WalkDir::new(&source).follow_links(false) {
+        let entry = direntry?;
+        let subpath = entry.path().strip_prefix(&source)?;
+        let dest = if subpath.as_os_str().is_empty() {
+            root.join(relpath)
+        } else {
+            root.join(relpath).join(subpath)
+        };
+        if entry.file_type().is_dir() {
+            if fs::metadata(&dest).is_err() {
+                fs::create_dir_all(&dest)?;
+            }
+            continue;
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::symlink_metadata(&dest).is_ok() {
+            trash.stash(&dest)?;
+        }
+        println!("Restoring {} from {}", dest.display(), stamp);
+        fs::rename(entry.path(), &dest)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_outside_are_rejected() {
+        assert!(check_relpath(Path::new("a/b.txt")).is_ok());
+        assert!(check_relpath(Path::new("./a")).is_ok());
+        assert!(check_relpath(Path::new("")).is_err());
+        assert!(check_relpath(Path::new("/etc/passwd")).is_err());
+        assert!(check_relpath(Path::new("../a")).is_err());
+        assert!(check_relpath(Path::new("a/../../b")).is_err());
+        let root = PathBuf::from("/nonexistent");
+        assert!(restore(&root, &PathBuf::from("../x"), None).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+use crate::pool::Pool;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+// Directories waiting to be read, shared by all threads
+struct Queue<C> {
+    dirs: Vec<(PathBuf, C)>,
+    busy: usize,
+    error: Option<String>,
+}
+
+fn read_dir_entries<T, C, F, V>(
+    dir: &Path,
+    context: &C,
+    filter: &F,
+    visit: &V,
+    found: &mut Vec<T>,
+    subdirs: &mut Vec<(PathBuf, C)>,
+) -> Result<(), String>
+where
+    F: Fn(&Path, &fs::DirEntry) -> bool,
+    V: Fn(&Path, &fs::DirEntry, &C) -> Result<(T, Option<C>), String>,
+{
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("{}: {}", dir.display(), e))?;
+        let path = entry.path();
+        if !filter(&path, &entry) {
+            continue;
+        }
+        let (item, subdir) = visit(&path, &entry, context)?;
+        if let Some(subdir_context) = subdir {
+            subdirs.push((path, subdir_context));
+        }
+        found.push(item);
+    }
+    Ok(())
+}
+
+/// Metadata of a directory entry, without following symlinks.
+pub fn entry_metadata(path: &Path) -> Result<fs::Metadata, String> {
+    fs::symlink_metadata(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Walk the tree below `start` without following symlinks, reading directories in parallel.
+/// Entries rejected by `filter` are skipped. `visit` is called from the worker threads for
+/// every other entry, with the context of the directory it is in. It returns the item to
+/// collect, and a context for the directory if it should be entered.
+/// `start` itself is not included. A slot of `pool` is taken for each directory that is read.
+pub fn walk_parallel<T, C, F, V>(
+    start: &Path,
+    context: C,
+    threads: usize,
+    pool: &Pool,
+    filter: F,
+    visit: V,
+) -> Result<Vec<T>, Box<dyn Error>>
+where
+    T: Send,
+    C: Send,
+    F: Fn(&Path, &fs::DirEntry) -> bool + Sync,
+    V: Fn(&Path, &fs::DirEntry, &C) -> Result<(T, Option<C>), String> + Sync,
+{
+    let queue = Mutex::new(Queue {
+        dirs: vec![(start.to_path_buf(), context)],
+        busy: 0,
+        error: None,
+    });
+    let wakeup = Condvar::new();
+    let results = Mutex::new(Vec::new());
+    thread::scope(|s| {
+        for _ in 0..threads.max(1) {
+            s.spawn(|| loop {
+                let (dir, context) = {
+                    let mut q = queue.lock().unwrap();
+                    loop {
+                        if q.error.is_some() {
+                            return;
+                        }
+                        if let Some(dir) = q.dirs.pop() {
+                            q.busy += 1;
+                            break dir;
+                        }
+                        if q.busy == 0 {
+                            return;
+                        }
+                        q = wakeup.wait(q).unwrap();
+                    }
+                };
+                let mut found = Vec::new();
+                let mut subdirs = Vec::new();
+                let result = {
+                    let _permit = pool.acquire();
+                    read_dir_entries(&dir, &context, &filter, &visit, &mut found, &mut subdirs)
+                };
+                results.lock().unwrap().append(&mut found);
+                let mut q = queue.lock().unwrap();
+                q.busy -= 1;
+                q.dirs.append(&mut subdirs);
+                if let Err(e) = result {
+                    q.error.get_or_insert(e);
+                }
+                wakeup.notify_all();
+            });
+        }
+    });
+    let queue = queue.into_inner().unwrap();
+    match queue.error {
+        Some(e) => Err(From::from(e)),
+        None => Ok(results.into_inner().unwrap()),
+    }
+}
@@ -0,0 +1,175 @@
+use crate::datatypes::is_tempfile;
+use crate::filter::PathFilter;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+// Network file systems, where changes made by other machines don't generate events
+const REMOTE_FS_TYPES: [u64; 9] = [
+    0x6969,      // NFS
+    0x517b,      // SMB
+    0xff53_4d42, // CIFS
+    0xfe53_4d42, // SMB2
+    0x6573_5546, // FUSE, for example sshfs
+    0x0102_1997, // 9P
+    0x00c3_6400, // Ceph
+    0x5346_414f, // AFS
+    0x0bd0_0bd0, // Lustre
+];
+
+/// Check if a path is on a network file system.
+pub fn is_remote(path: &Path) -> bool {
+    let cpath = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(cpath) => cpath,
+        Err(_) => return false,
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::statfs(cpath.as_ptr(), &mut stat) };
+    res == 0 && REMOTE_FS_TYPES.contains(&(stat.f_type as u64 & 0xffff_ffff))
+}
+
+fn watch_mask() -> WatchMask {
+    WatchMask::MODIFY
+        | WatchMask::ATTRIB
+        | WatchMask::CLOSE_WRITE
+        | WatchMask::CREATE
+        | WatchMask::DELETE
+        | WatchMask::DELETE_SELF
+        | WatchMask::MOVE_SELF
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO
+        | WatchMask::DONT_FOLLOW
+        | WatchMask::ONLYDIR
+        | WatchMask::EXCL_UNLINK
+}
+
+/// Records the paths that changed in a directory tree, using inotify.
+/// Every directory in the tree gets its own watch.
+pub struct ChangeWatcher {
+    inotify: Inotify,
+    root: PathBuf,
+    filter: PathFilter,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl ChangeWatcher {
+    pub fn new(root: &Path, filter: &PathFilter) -> Result<ChangeWatcher, Box<dyn Error>> {
+        if is_remote(root) {
+            return Err(From::from("network file system"));
+        }
+        let mut watcher = ChangeWatcher {
+            inotify: Inotify::init()?,
+            root: root.to_path_buf(),
+            filter: filter.clone(),
+            dirs: HashMap::new(),
+        };
+        watcher.watch_tree(&PathBuf::new())?;
+        Ok(watcher)
+    }
+
+    fn is_excluded(&self, relpath: &Path, is_dir: bool) -> bool {
+        is_tempfile(relpath) || self.filter.is_excluded(relpath, is_dir)
+    }
+
+    // Add watches for a directory and all directories below it.
+    // Only running out of watches is an error, directories may disappear while walking.
+    fn watch_tree(&mut self, start: &PathBuf) -> Result<(), Box<dyn Error>> {
+        let root = self.root.clone();
+        let walker = WalkDir::new(root.join(start))
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|e| match e.path().strip_prefix(&root) {
+                Ok(relpath) => {
+                    relpath.as_os_str().is_empty()
+                        || !self.is_excluded(relpath, e.file_type().is_dir())
+                }
+                Err(_) => false,
+            });
+        let mut new_dirs = Vec::new();
+        for entry in walker.flatten() {
+            if entry.file_type().is_dir() {
+                new_dirs.push(entry.into_path());
+            }
+        }
+        for dir in new_dirs {
+            match self.inotify.watches().add(&dir, watch_mask()) {
+                Ok(wd) => {
+                    let relpath = dir.strip_prefix(&root)?.to_path_buf();
+                    self.dirs.insert(wd, relpath);
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENOSPC) => {
+                    return Err(From::from(
+                        "out of inotify watches, increase fs.inotify.max_user_watches",
+                    ));
+                }
+                Err(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect the paths that changed since the last call, relative to the root.
+    /// Returns None if events were lost, then a full scan is needed.
+    pub fn changes(&mut self) -> Option<Vec<PathBuf>> {
+        let mut dirty = HashSet::new();
+        let mut new_dirs = Vec::new();
+        let mut lost = false;
+        let mut buffer = [0; 4096];
+        loop {
+            let events = match self.inotify.read_events(&mut buffer) {
+                Ok(events) => events,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    lost = true;
+                    break;
+                }
+            };
+            let mut count = 0;
+            for event in events {
+                count += 1;
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    lost = true;
+                    continue;
+                }
+                if event.mask.contains(EventMask::IGNORED) {
+                    self.dirs.remove(&event.wd);
+                    continue;
+                }
+                let path = match (self.dirs.get(&event.wd), event.name) {
+                    (Some(dir), Some(name)) => dir.join(name),
+                    (Some(dir), None) => dir.to_path_buf(),
+                    (None, _) => continue,
+                };
+                let is_dir = event.mask.contains(EventMask::ISDIR);
+                if !path.as_os_str().is_empty() && self.is_excluded(&path, is_dir) {
+                    continue;
+                }
+                if is_dir
+                    && (event.mask.contains(EventMask::CREATE)
+                        || event.mask.contains(EventMask::MOVED_TO))
+                {
+                    new_dirs.push(path.clone());
+                }
+                dirty.insert(path);
+            }
+            if count == 0 {
+                break;
+            }
+        }
+        for dir in new_dirs.iter() {
+            if self.watch_tree(dir).is_err() {
+                lost = true;
+            }
+        }
+        if lost {
+            None
+        } else {
+            Some(dirty.into_iter().collect())
+        }
+    }
+}